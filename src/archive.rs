@@ -0,0 +1,152 @@
+use crate::error::{DedupError, IoResultExt, Result};
+use crate::models::FileInfo;
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// Separator between an archive's path and an entry inside it, e.g. `backup.zip!a.txt`
+pub const ENTRY_SEPARATOR: char = '!';
+
+enum Kind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+fn archive_error(path: &Path, source: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> DedupError {
+    DedupError::Archive {
+        path: path.to_path_buf(),
+        source: source.into(),
+    }
+}
+
+fn kind(path: &Path) -> Option<Kind> {
+    let name = path.file_name()?.to_string_lossy().to_lowercase();
+    if name.ends_with(".zip") {
+        Some(Kind::Zip)
+    } else if name.ends_with(".tar") {
+        Some(Kind::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(Kind::TarGz)
+    } else {
+        None
+    }
+}
+
+/// Check if a file is an archive whose entries can be scanned
+pub fn is_archive(path: &Path) -> bool {
+    kind(path).is_some()
+}
+
+fn open_tar(path: &Path, kind: &Kind) -> Result<tar::Archive<Box<dyn Read>>> {
+    let file = BufReader::new(File::open(path).at(path)?);
+    let reader: Box<dyn Read> = match kind {
+        Kind::TarGz => Box::new(GzDecoder::new(file)),
+        _ => Box::new(file),
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+/// List the regular files inside an archive as virtual [`FileInfo`]s. They
+/// share the archive's mtime so any change to the archive invalidates them.
+pub fn list_entries(archive: &FileInfo) -> Result<Vec<FileInfo>> {
+    let path = &archive.path;
+    let kind = kind(path).ok_or_else(|| archive_error(path, "Not a supported archive"))?;
+    let mut names = Vec::new();
+
+    match kind {
+        Kind::Zip => {
+            let file = BufReader::new(File::open(path).at(path)?);
+            let mut zip = zip::ZipArchive::new(file).map_err(|e| archive_error(path, e))?;
+            for i in 0..zip.len() {
+                let entry = zip.by_index(i).map_err(|e| archive_error(path, e))?;
+                if entry.is_file() {
+                    names.push((entry.name().to_string(), entry.size()));
+                }
+            }
+        }
+        Kind::Tar | Kind::TarGz => {
+            let mut tar = open_tar(path, &kind)?;
+            for entry in tar.entries().map_err(|e| archive_error(path, e))? {
+                let entry = entry.map_err(|e| archive_error(path, e))?;
+                if entry.header().entry_type().is_file() {
+                    let name = entry.path().map_err(|e| archive_error(path, e))?;
+                    names.push((name.to_string_lossy().into_owned(), entry.size()));
+                }
+            }
+        }
+    }
+
+    Ok(names
+        .into_iter()
+        .map(|(name, size)| FileInfo {
+            path: archive.path.clone(),
+            rel_path: format!("{}{}{}", archive.rel_path, ENTRY_SEPARATOR, name),
+            key_path: format!("{}{}{}", archive.key_path, ENTRY_SEPARATOR, name),
+            size,
+            mtime: archive.mtime,
+            keep_time: archive.keep_time,
+            inode: None,
+            dev: None,
+            link_target: None,
+            archive_entry: Some(name),
+            allocated: None,
+        })
+        .collect())
+}
+
+/// Pass the name and content of every regular file inside an archive to `f`,
+/// in a single pass over the archive. Stops at the first error `f` returns.
+pub fn for_each_entry(archive: &Path, mut f: impl FnMut(&str, &mut dyn Read) -> Result<()>) -> Result<()> {
+    let kind = kind(archive).ok_or_else(|| archive_error(archive, "Not a supported archive"))?;
+    match kind {
+        Kind::Zip => {
+            let file = BufReader::new(File::open(archive).at(archive)?);
+            let mut zip = zip::ZipArchive::new(file).map_err(|e| archive_error(archive, e))?;
+            for i in 0..zip.len() {
+                let mut entry = zip.by_index(i).map_err(|e| archive_error(archive, e))?;
+                if entry.is_file() {
+                    let name = entry.name().to_string();
+                    f(&name, &mut entry)?;
+                }
+            }
+        }
+        Kind::Tar | Kind::TarGz => {
+            let mut tar = open_tar(archive, &kind)?;
+            for entry in tar.entries().map_err(|e| archive_error(archive, e))? {
+                let mut entry = entry.map_err(|e| archive_error(archive, e))?;
+                if entry.header().entry_type().is_file() {
+                    let name = entry.path().map_err(|e| archive_error(archive, e))?.to_string_lossy().into_owned();
+                    f(&name, &mut entry)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Open a single entry of an archive and pass its content to `f`. Finding a
+/// tar entry means reading the archive up to it, see [`for_each_entry`] for
+/// reading many.
+pub fn with_entry<T>(archive: &Path, entry: &str, f: impl FnOnce(&mut dyn Read) -> Result<T>) -> Result<T> {
+    let kind = kind(archive).ok_or_else(|| archive_error(archive, "Not a supported archive"))?;
+    match kind {
+        Kind::Zip => {
+            let file = BufReader::new(File::open(archive).at(archive)?);
+            let mut zip = zip::ZipArchive::new(file).map_err(|e| archive_error(archive, e))?;
+            let mut file = zip.by_name(entry).map_err(|e| archive_error(archive, e))?;
+            f(&mut file)
+        }
+        Kind::Tar | Kind::TarGz => {
+            let mut tar = open_tar(archive, &kind)?;
+            for file in tar.entries().map_err(|e| archive_error(archive, e))? {
+                let mut file = file.map_err(|e| archive_error(archive, e))?;
+                if file.path().map_err(|e| archive_error(archive, e))?.to_string_lossy() == entry {
+                    return f(&mut file);
+                }
+            }
+            Err(archive_error(archive, format!("Entry {} not found", entry)))
+        }
+    }
+}
@@ -1,99 +1,476 @@
-use crate::hashing::validate_hash;
-use crate::models::{Algorithm, HashEntry};
-use anyhow::Result;
+use crate::error::{DedupError, IoResultExt, Result};
+use crate::hashing::{decode_hash, encode_hash, validate_hash};
+use crate::models::{Algorithm, CacheFormat, HashEncoding, HashEntry};
+use clap::ValueEnum;
+use rayon::prelude::*;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::cell::OnceCell;
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+const CACHE_FILE_PREFIX: &str = "duplicates.";
+const CACHE_FILE_STEM: &str = "hashes";
+/// CSV caches are parsed in parallel chunks of about this many bytes
+const CSV_CHUNK_BYTES: usize = 4 * 1024 * 1024;
+/// Extensions a cache file is recognized by, SQLite ones first
+const SQLITE_EXTENSIONS: [&str; 2] = ["db", "sqlite"];
+
+/// Name of the cache file, optionally namespaced by algorithm (e.g. `duplicates.sha256.hashes.csv`)
+pub fn cache_file_name(algo: Option<Algorithm>, format: CacheFormat) -> String {
+    match algo.and_then(|a| a.to_possible_value()) {
+        Some(value) => format!(
+            "{}{}.{}.{}",
+            CACHE_FILE_PREFIX,
+            value.get_name(),
+            CACHE_FILE_STEM,
+            format.extension()
+        ),
+        None => format!("{}{}.{}", CACHE_FILE_PREFIX, CACHE_FILE_STEM, format.extension()),
+    }
+}
+
+/// Classify a file name: `None` if it is not a cache file, `Some(None)` for the
+/// shared cache and `Some(Some(algo))` for an algorithm-namespaced one
+pub fn parse_cache_file_name(name: &str) -> Option<Option<Algorithm>> {
+    let (rest, extension) = name.strip_prefix(CACHE_FILE_PREFIX)?.rsplit_once('.')?;
+    if extension != CacheFormat::Csv.extension() && !SQLITE_EXTENSIONS.contains(&extension) {
+        return None;
+    }
+    let middle = rest.strip_suffix(CACHE_FILE_STEM)?;
+    if middle.is_empty() {
+        return Some(None);
+    }
+    let algo = Algorithm::from_str(middle.strip_suffix('.')?, true).ok()?;
+    Some(Some(algo))
+}
+
+/// Check if a file is a journal SQLite keeps next to a cache database (`-wal`/`-shm`)
+pub fn is_cache_sidecar(name: &str) -> bool {
+    name.strip_suffix("-wal")
+        .or_else(|| name.strip_suffix("-shm"))
+        .is_some_and(|db| parse_cache_file_name(db).is_some())
+}
+
+/// Backend of a cache file, detected by its extension (`.db`/`.sqlite` is SQLite, anything else CSV)
+pub fn cache_format(path: &Path) -> CacheFormat {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+    match extension {
+        Some(e) if SQLITE_EXTENSIONS.contains(&e.as_str()) => CacheFormat::Sqlite,
+        _ => CacheFormat::Csv,
+    }
+}
+
+fn cache_error(path: &Path, source: impl std::error::Error + Send + Sync + 'static) -> DedupError {
+    DedupError::Cache {
+        path: path.to_path_buf(),
+        source: Box::new(source),
+    }
+}
+
+fn algo_name(algo: Algorithm) -> String {
+    algo.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default()
+}
+
+/// Identifies a cached hash: the file's path, size and mtime plus the algorithm used
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub path: String,
+    pub size: u64,
+    pub time: u64,
+    pub algo: Algorithm,
+}
+
+/// A persistent home for hash entries. Paths are stored relative to the store's own location.
+pub trait CacheStore: Send {
+    /// Read every entry in the store
+    fn load(&self) -> Result<HashMap<CacheKey, String>>;
+    /// Whether [`CacheStore::get`] is a cheap point query, so the store needn't be loaded up front
+    fn indexed(&self) -> bool {
+        false
+    }
+    /// Read the entry with exactly `key`, if there is one
+    fn get(&self, key: &CacheKey) -> Result<Option<String>> {
+        Ok(self.load()?.remove(key))
+    }
+    /// Persist new entries, replacing any with the same key
+    fn append(&mut self, entries: &[HashEntry]) -> Result<()>;
+}
+
+/// Open the store for a cache file, picking the backend by its extension
+pub fn open_store(path: &Path) -> Box<dyn CacheStore> {
+    match cache_format(path) {
+        CacheFormat::Csv => Box::new(CsvStore::new(path.to_path_buf())),
+        CacheFormat::Sqlite => Box::new(SqliteStore::new(path.to_path_buf())),
+    }
+}
+
+/// Split CSV data into chunks of about `target` bytes, cutting only at line
+/// ends outside quoted fields so no record is torn apart
+fn split_records(data: &[u8], target: usize) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut in_quotes = false;
+    for (i, &b) in data.iter().enumerate() {
+        match b {
+            b'"' => in_quotes = !in_quotes,
+            b'\n' if !in_quotes && i + 1 - start >= target => {
+                chunks.push(&data[start..=i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < data.len() {
+        chunks.push(&data[start..]);
+    }
+    chunks
+}
+
+/// `;`-separated CSV file, only ever appended to
+pub struct CsvStore {
+    path: PathBuf,
+}
+
+impl CsvStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl CacheStore for CsvStore {
+    fn load(&self) -> Result<HashMap<CacheKey, String>> {
+        let data = std::fs::read(&self.path).at(&self.path)?;
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(b';')
+            .from_reader(data.as_slice());
+        let headers = rdr.byte_headers().map_err(|e| cache_error(&self.path, e))?.clone();
+        let body = &data[rdr.position().byte() as usize..];
+
+        // Rows that fail to parse are skipped, like a sequential read would
+        Ok(split_records(body, CSV_CHUNK_BYTES)
+            .into_par_iter()
+            .flat_map_iter(|chunk| {
+                let mut rdr = csv::ReaderBuilder::new()
+                    .delimiter(b';')
+                    .has_headers(false)
+                    .from_reader(chunk);
+                rdr.byte_records()
+                    .flatten()
+                    .filter_map(|r| r.deserialize::<HashEntry>(Some(&headers)).ok())
+                    .map(|e| (CacheKey { path: e.path, size: e.size, time: e.time, algo: e.algo }, e.hash))
+                    .collect::<Vec<_>>()
+            })
+            .collect())
+    }
+
+    fn append(&mut self, entries: &[HashEntry]) -> Result<()> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .at(&self.path)?;
+
+        let needs_header = std::fs::metadata(&self.path).at(&self.path)?.len() == 0;
+
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b';')
+            .has_headers(needs_header)
+            .from_writer(file);
+
+        for entry in entries {
+            wtr.serialize(entry).map_err(|e| cache_error(&self.path, e))?;
+        }
+        wtr.flush().at(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Open a SQLite cache, creating the table and its `(path, size, time, algo)` index if needed
+fn open_sqlite(path: &Path) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(
+        "PRAGMA journal_mode = WAL;
+         PRAGMA synchronous = NORMAL;
+         CREATE TABLE IF NOT EXISTS hashes (
+             path TEXT NOT NULL,
+             size INTEGER NOT NULL,
+             time INTEGER NOT NULL,
+             algo TEXT NOT NULL,
+             hash TEXT NOT NULL,
+             PRIMARY KEY (path, size, time, algo)
+         );",
+    )?;
+    Ok(conn)
+}
+
+/// SQLite database with a `(path, size, time, algo)` primary key, updated in place
+pub struct SqliteStore {
+    path: PathBuf,
+    /// Opened on the first write, loading uses its own read-only connection
+    conn: Option<Connection>,
+    /// Read-only connection for point lookups, opened on the first one
+    reader: OnceCell<Connection>,
+}
+
+impl SqliteStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            conn: None,
+            reader: OnceCell::new(),
+        }
+    }
+
+    fn reader(&self) -> rusqlite::Result<&Connection> {
+        if let Some(conn) = self.reader.get() {
+            return Ok(conn);
+        }
+        let conn = Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Ok(self.reader.get_or_init(|| conn))
+    }
+
+    /// Look up one row through the primary key
+    fn read_row(&self, key: &CacheKey) -> rusqlite::Result<Option<String>> {
+        let mut stmt = self
+            .reader()?
+            .prepare_cached("SELECT hash FROM hashes WHERE path = ?1 AND size = ?2 AND time = ?3 AND algo = ?4")?;
+        stmt.query_row(params![key.path, key.size, key.time, algo_name(key.algo)], |row| row.get(0))
+            .optional()
+    }
+
+    fn read_rows(&self) -> rusqlite::Result<HashMap<CacheKey, String>> {
+        let conn = Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let mut stmt = conn.prepare("SELECT path, size, time, algo, hash FROM hashes")?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, u64>(1)?,
+                    row.get::<_, u64>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?,
+                ))
+            })?
+            .flatten()
+            .filter_map(|(path, size, time, algo, hash)| {
+                let algo = Algorithm::from_str(&algo, true).ok()?;
+                Some((CacheKey { path, size, time, algo }, hash))
+            })
+            .collect();
+        Ok(rows)
+    }
+
+    fn write_rows(&mut self, entries: &[HashEntry]) -> rusqlite::Result<()> {
+        if self.conn.is_none() {
+            self.conn = Some(open_sqlite(&self.path)?);
+        }
+        let conn = self.conn.as_mut().expect("connection was just opened");
+        let tx = conn.transaction()?;
+        for entry in entries {
+            tx.execute(
+                "INSERT OR REPLACE INTO hashes (path, size, time, algo, hash) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![entry.path, entry.size, entry.time, algo_name(entry.algo), entry.hash],
+            )?;
+        }
+        tx.commit()
+    }
+}
+
+impl CacheStore for SqliteStore {
+    fn load(&self) -> Result<HashMap<CacheKey, String>> {
+        self.read_rows().map_err(|e| cache_error(&self.path, e))
+    }
+
+    fn indexed(&self) -> bool {
+        true
+    }
+
+    fn get(&self, key: &CacheKey) -> Result<Option<String>> {
+        self.read_row(key).map_err(|e| cache_error(&self.path, e))
+    }
+
+    fn append(&mut self, entries: &[HashEntry]) -> Result<()> {
+        self.write_rows(entries).map_err(|e| cache_error(&self.path, e))
+    }
+}
+
+/// Cache column value of a hash in `encoding`. Hex hashes are stored as is, so
+/// caches stay readable by the script versions; others get an `encoding:` tag.
+fn stored_hash(hash: &str, encoding: HashEncoding) -> String {
+    match encoding {
+        HashEncoding::Hex => hash.to_string(),
+        encoding => format!("{}:{}", encoding.tag(), hash),
+    }
+}
+
+/// A cached hash converted to `wanted`, `None` if it can't be decoded
+fn loaded_hash(stored: &str, wanted: HashEncoding) -> Option<String> {
+    let (encoding, hash) = match stored.split_once(':') {
+        Some((tag, hash)) => (HashEncoding::value_variants().iter().copied().find(|e| e.tag() == tag)?, hash),
+        None => (HashEncoding::Hex, stored),
+    };
+    if encoding == wanted {
+        return Some(hash.to_string());
+    }
+    Some(encode_hash(&decode_hash(hash, encoding)?, wanted))
+}
+
+/// In-memory view of all loaded hashes for one algorithm, writing new ones through to a [`CacheStore`]
 pub struct HashCache {
-    cache: HashMap<String, String>,
-    csv_path: std::path::PathBuf,
-    base_path: std::path::PathBuf,
+    cache: HashMap<CacheKey, String>,
+    /// [Indexed](CacheStore::indexed) stores, queried on every lookup instead of being loaded, with their directories
+    indexed: Vec<(Box<dyn CacheStore>, PathBuf)>,
+    store: Box<dyn CacheStore>,
+    base_path: PathBuf,
+    algo: Algorithm,
+    /// Encoding the hashes are held and appended in, loaded ones are converted to it
+    encoding: HashEncoding,
 }
 
 impl HashCache {
-    /// Create a new HashCache
-    pub fn new(csv_path: std::path::PathBuf, base_path: std::path::PathBuf) -> Self {
+    /// Create a new HashCache that only keeps entries for `algo` and writes new ones to `store`
+    pub fn new(store: Box<dyn CacheStore>, base_path: PathBuf, algo: Algorithm, encoding: HashEncoding) -> Self {
         Self {
             cache: HashMap::new(),
-            csv_path,
+            indexed: Vec::new(),
+            store,
             base_path,
+            algo,
+            encoding,
+        }
+    }
+
+    /// Merge the entries of a store located in `store_dir` into the cache
+    pub fn load(&mut self, store: &dyn CacheStore, store_dir: &Path) -> Result<usize> {
+        self.load_matching(store, store_dir, |_| true)
+    }
+
+    /// Use the store of a cache file located in `store_dir`. An indexed store is
+    /// kept and queried on each lookup, any other is loaded through
+    /// [`HashCache::load_matching`]. Returns the number of hashes loaded.
+    pub fn attach(
+        &mut self,
+        store: Box<dyn CacheStore>,
+        store_dir: &Path,
+        wanted: impl Fn(&CacheKey) -> bool,
+    ) -> Result<usize> {
+        if store.indexed() {
+            self.indexed.push((store, store_dir.to_path_buf()));
+            return Ok(0);
         }
+        self.load_matching(&*store, store_dir, wanted)
+    }
+
+    /// A stored hash in this cache's encoding, `None` if it isn't a valid `algo` hash
+    fn accept(&self, stored: &str, algo: Algorithm) -> Option<String> {
+        loaded_hash(stored, self.encoding).filter(|hash| validate_hash(hash, algo, self.encoding))
     }
 
-    /// Load a hash CSV file and merge entries into the cache
-    pub fn load_csv(&mut self, csv_path: &Path) -> Result<usize> {
+    /// Like [`HashCache::load`], but only hold on to the entries `wanted`
+    /// accepts, keyed by their path relative to the cache's base path
+    pub fn load_matching(
+        &mut self,
+        store: &dyn CacheStore,
+        store_dir: &Path,
+        wanted: impl Fn(&CacheKey) -> bool,
+    ) -> Result<usize> {
         let mut loaded = 0;
-        let mut rdr = csv::ReaderBuilder::new()
-            .delimiter(b';')
-            .from_path(csv_path)?;
-
-        // Get the directory containing the CSV file
-        let csv_dir = csv_path.parent().unwrap_or(&self.base_path);
-
-        for result in rdr.deserialize() {
-            if let Ok(entry) = result {
-                let entry: HashEntry = entry;
-                // Validate hash before adding to cache
-                if validate_hash(&entry.hash, entry.algo) {
-                    // Adjust path relative to the CSV's location
-                    let adjusted_path = if entry.path.starts_with('/') || entry.path.starts_with('\\') {
-                        entry.path.clone()
-                    } else {
-                        csv_dir
-                            .join(&entry.path)
-                            .strip_prefix(&self.base_path)
-                            .unwrap_or(Path::new(&entry.path))
-                            .to_string_lossy()
-                            .into_owned()
-                    };
-
-                    let key = format!(
-                        "{}|{}|{}|{:?}",
-                        adjusted_path, entry.size, entry.time, entry.algo
-                    );
-                    self.cache.insert(key, entry.hash);
-                    loaded += 1;
-                }
+        for (key, hash) in store.load()? {
+            // Rows for other algorithms could never be hit, so don't hold them
+            if key.algo != self.algo {
+                continue;
             }
+            let Some(hash) = self.accept(&hash, key.algo) else {
+                continue;
+            };
+            // Adjust path relative to the store's location
+            let path = if key.path.starts_with('/') || key.path.starts_with('\\') {
+                key.path
+            } else {
+                store_dir
+                    .join(&key.path)
+                    .strip_prefix(&self.base_path)
+                    .unwrap_or(Path::new(&key.path))
+                    .to_string_lossy()
+                    .into_owned()
+            };
+            let key = CacheKey { path, ..key };
+            if !wanted(&key) {
+                continue;
+            }
+            self.cache.insert(key, hash);
+            loaded += 1;
         }
-
         Ok(loaded)
     }
 
+    /// Query the indexed stores for `key`, its path made relative to each store's directory
+    fn lookup(&self, key: &CacheKey) -> Option<String> {
+        if key.algo != self.algo {
+            return None;
+        }
+        let full_path = self.base_path.join(&key.path);
+        self.indexed.iter().find_map(|(store, store_dir)| {
+            let path = full_path.strip_prefix(store_dir).ok()?.to_string_lossy().into_owned();
+            let stored = store.get(&CacheKey { path, ..key.clone() }).ok()??;
+            self.accept(&stored, key.algo)
+        })
+    }
+
+    fn key(path: &str, size: u64, mtime: u64, algo: Algorithm) -> CacheKey {
+        CacheKey {
+            path: path.to_string(),
+            size,
+            time: mtime,
+            algo,
+        }
+    }
+
     /// Get a hash from the cache
-    pub fn get(&self, path: &str, size: u64, mtime: u64, algo: Algorithm) -> Option<&String> {
-        let key = format!("{}|{}|{}|{:?}", path, size, mtime, algo);
-        self.cache.get(&key)
+    pub fn get(&self, path: &str, size: u64, mtime: u64, algo: Algorithm) -> Option<String> {
+        let key = Self::key(path, size, mtime, algo);
+        self.cache.get(&key).cloned().or_else(|| self.lookup(&key))
+    }
+
+    /// Remove a hash from the cache, for lookups that won't be repeated
+    pub fn take(&mut self, path: &str, size: u64, mtime: u64, algo: Algorithm) -> Option<String> {
+        let key = Self::key(path, size, mtime, algo);
+        self.cache.remove(&key).or_else(|| self.lookup(&key))
     }
 
     /// Check if cache contains an entry
     #[allow(dead_code)]
     pub fn contains(&self, path: &str, size: u64, mtime: u64, algo: Algorithm) -> bool {
-        let key = format!("{}|{}|{}|{:?}", path, size, mtime, algo);
-        self.cache.contains_key(&key)
+        self.get(path, size, mtime, algo).is_some()
     }
 
-    /// Append a new hash entry to the CSV file
-    pub fn append(&self, entry: &HashEntry) -> Result<()> {
-        let file = std::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.csv_path)?;
-
-        let needs_header = !self.csv_path.exists() 
-            || std::fs::metadata(&self.csv_path)?.len() == 0;
-
-        let mut wtr = csv::WriterBuilder::new()
-            .delimiter(b';')
-            .has_headers(needs_header)
-            .from_writer(file);
+    /// Append a new hash entry to the backing store
+    pub fn append(&mut self, entry: &HashEntry) -> Result<()> {
+        self.append_all(std::slice::from_ref(entry))
+    }
 
-        wtr.serialize(entry)?;
-        wtr.flush()?;
-        Ok(())
+    /// Append several hash entries to the backing store in one write
+    pub fn append_all(&mut self, entries: &[HashEntry]) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        if self.encoding == HashEncoding::Hex {
+            return self.store.append(entries);
+        }
+        let tagged: Vec<HashEntry> = entries
+            .iter()
+            .map(|e| HashEntry {
+                path: e.path.clone(),
+                size: e.size,
+                time: e.time,
+                algo: e.algo,
+                hash: stored_hash(&e.hash, self.encoding),
+            })
+            .collect();
+        self.store.append(&tagged)
     }
 
-    /// Get the number of cached hashes
+    /// Get the number of hashes loaded into memory
     #[allow(dead_code)]
     pub fn len(&self) -> usize {
         self.cache.len()
@@ -107,7 +484,247 @@ impl HashCache {
 
     /// Get a reference to the internal cache map
     #[allow(dead_code)]
-    pub fn inner(&self) -> &HashMap<String, String> {
+    pub fn inner(&self) -> &HashMap<CacheKey, String> {
         &self.cache
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Store holding its entries in memory, shared between clones
+    #[derive(Clone, Default)]
+    struct MemoryStore {
+        entries: Arc<Mutex<HashMap<CacheKey, String>>>,
+    }
+
+    impl CacheStore for MemoryStore {
+        fn load(&self) -> Result<HashMap<CacheKey, String>> {
+            Ok(self.entries.lock().unwrap().clone())
+        }
+
+        fn append(&mut self, entries: &[HashEntry]) -> Result<()> {
+            let mut stored = self.entries.lock().unwrap();
+            for e in entries {
+                let key = CacheKey { path: e.path.clone(), size: e.size, time: e.time, algo: e.algo };
+                stored.insert(key, e.hash.clone());
+            }
+            Ok(())
+        }
+    }
+
+    fn entry(path: &str, algo: Algorithm, hash: &str) -> HashEntry {
+        HashEntry {
+            path: path.to_string(),
+            size: 4,
+            time: 1,
+            algo,
+            hash: hash.to_string(),
+        }
+    }
+
+    const MD5: &str = "0123456789abcdef0123456789abcdef";
+    const SHA256: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn namespaced_cache_names_are_recognized_by_algorithm() {
+        let name = cache_file_name(Some(Algorithm::Sha256), CacheFormat::Csv);
+        assert_eq!(name, "duplicates.sha256.hashes.csv");
+        assert_eq!(parse_cache_file_name(&name), Some(Some(Algorithm::Sha256)));
+        assert_eq!(parse_cache_file_name("duplicates.hashes.csv"), Some(None));
+        assert_eq!(parse_cache_file_name("duplicates.md5.hashes.db"), Some(Some(Algorithm::Md5)));
+        assert_eq!(parse_cache_file_name("duplicates.log"), None);
+        assert_eq!(parse_cache_file_name("duplicates.nope.hashes.csv"), None);
+    }
+
+    #[test]
+    fn only_rows_of_the_cache_algorithm_are_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut shared = CsvStore::new(dir.path().join(cache_file_name(None, CacheFormat::Csv)));
+        shared
+            .append(&[entry("a.txt", Algorithm::Md5, MD5), entry("a.txt", Algorithm::Sha256, SHA256)])
+            .unwrap();
+        let mut namespaced = CsvStore::new(dir.path().join(cache_file_name(Some(Algorithm::Sha256), CacheFormat::Csv)));
+        namespaced.append(&[entry("b.txt", Algorithm::Sha256, SHA256)]).unwrap();
+
+        let new_cache = || Box::new(CsvStore::new(dir.path().join("new.csv")));
+        let new_hash_cache = |algo| HashCache::new(new_cache(), dir.path().to_path_buf(), algo, HashEncoding::Hex);
+        let mut cache = new_hash_cache(Algorithm::Sha256);
+        assert_eq!(cache.load(&shared, dir.path()).unwrap(), 1);
+        assert_eq!(cache.load(&namespaced, dir.path()).unwrap(), 1);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("a.txt", 4, 1, Algorithm::Sha256).as_deref(), Some(SHA256));
+        assert!(!cache.contains("a.txt", 4, 1, Algorithm::Md5));
+
+        let mut cache = new_hash_cache(Algorithm::Md5);
+        assert_eq!(cache.load(&shared, dir.path()).unwrap(), 1);
+        assert_eq!(cache.get("a.txt", 4, 1, Algorithm::Md5).as_deref(), Some(MD5));
+    }
+
+    #[test]
+    fn sqlite_and_csv_stores_load_the_same_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries = [
+            entry("a.txt", Algorithm::Md5, MD5),
+            entry("sub/b;c.txt", Algorithm::Sha256, SHA256),
+            HashEntry { time: 2, ..entry("a.txt", Algorithm::Md5, MD5) },
+        ];
+        let mut csv = open_store(&dir.path().join("duplicates.hashes.csv"));
+        let mut sqlite = open_store(&dir.path().join("duplicates.hashes.db"));
+        for store in [&mut csv, &mut sqlite] {
+            store.append(&entries[..1]).unwrap();
+            store.append(&entries[1..]).unwrap();
+        }
+
+        let loaded = sqlite.load().unwrap();
+        assert_eq!(loaded.len(), 3);
+        assert_eq!(loaded, csv.load().unwrap());
+        let key = CacheKey { path: "sub/b;c.txt".to_string(), size: 4, time: 1, algo: Algorithm::Sha256 };
+        assert_eq!(loaded[&key], SHA256);
+
+        // SQLite replaces an entry with the same key in place
+        sqlite.append(&[entry("a.txt", Algorithm::Md5, &MD5.replace('0', "f"))]).unwrap();
+        assert_eq!(sqlite.load().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn sqlite_caches_are_queried_per_file_instead_of_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let sub = dir.path().join("sub");
+        std::fs::create_dir(&sub).unwrap();
+        let entries = [
+            entry("a.txt", Algorithm::Md5, MD5),
+            entry("a.txt", Algorithm::Sha256, SHA256),
+            entry("bad.txt", Algorithm::Md5, "zz"),
+        ];
+        let (db, csv) = (sub.join("duplicates.hashes.db"), sub.join("duplicates.hashes.csv"));
+        for path in [&db, &csv] {
+            open_store(path).append(&entries).unwrap();
+        }
+        let (sqlite, csv_store) = (open_store(&db), open_store(&csv));
+        assert!(sqlite.indexed() && !csv_store.indexed());
+        for key in [
+            CacheKey { path: "a.txt".to_string(), size: 4, time: 1, algo: Algorithm::Sha256 },
+            CacheKey { path: "a.txt".to_string(), size: 4, time: 2, algo: Algorithm::Md5 },
+            CacheKey { path: "b.txt".to_string(), size: 4, time: 1, algo: Algorithm::Md5 },
+        ] {
+            assert_eq!(sqlite.get(&key).unwrap(), csv_store.get(&key).unwrap(), "{:?}", key);
+        }
+
+        let new_store = Box::new(MemoryStore::default());
+        let mut cache = HashCache::new(new_store, dir.path().to_path_buf(), Algorithm::Md5, HashEncoding::Hex);
+        assert_eq!(cache.attach(sqlite, &sub, |_| true).unwrap(), 0);
+        assert!(cache.is_empty());
+        let in_sub = |name: &str| Path::new("sub").join(name).to_string_lossy().into_owned();
+        assert_eq!(cache.get(&in_sub("a.txt"), 4, 1, Algorithm::Md5).as_deref(), Some(MD5));
+        assert_eq!(cache.take(&in_sub("a.txt"), 4, 1, Algorithm::Md5).as_deref(), Some(MD5));
+        // Other algorithms, other mtimes and invalid hashes are misses
+        assert!(!cache.contains(&in_sub("a.txt"), 4, 1, Algorithm::Sha256));
+        assert!(!cache.contains(&in_sub("a.txt"), 4, 2, Algorithm::Md5));
+        assert!(!cache.contains(&in_sub("bad.txt"), 4, 1, Algorithm::Md5));
+        assert!(!cache.contains("a.txt", 4, 1, Algorithm::Md5));
+    }
+
+    #[test]
+    fn hash_cache_works_against_any_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = MemoryStore::default();
+        let mut cache =
+            HashCache::new(Box::new(store.clone()), dir.path().to_path_buf(), Algorithm::Md5, HashEncoding::Hex);
+        cache.append(&entry("a.txt", Algorithm::Md5, MD5)).unwrap();
+        cache
+            .append_all(&[entry("b.txt", Algorithm::Md5, MD5), entry("bad.txt", Algorithm::Md5, "zz")])
+            .unwrap();
+        assert_eq!(store.entries.lock().unwrap().len(), 3);
+        // Writes go to the store only, the cache holds what was loaded
+        assert!(cache.is_empty());
+
+        // Paths are relative to the store, which here sits in a subdirectory
+        let sub = dir.path().join("sub");
+        let new_store = Box::new(MemoryStore::default());
+        let mut cache = HashCache::new(new_store, dir.path().to_path_buf(), Algorithm::Md5, HashEncoding::Hex);
+        let in_sub = |name: &str| Path::new("sub").join(name).to_string_lossy().into_owned();
+        assert_eq!(cache.load(&store, &sub).unwrap(), 2);
+        assert_eq!(cache.get(&in_sub("a.txt"), 4, 1, Algorithm::Md5).as_deref(), Some(MD5));
+        assert!(!cache.contains(&in_sub("bad.txt"), 4, 1, Algorithm::Md5));
+
+        assert_eq!(cache.take(&in_sub("b.txt"), 4, 1, Algorithm::Md5).as_deref(), Some(MD5));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn cached_hashes_are_converted_to_the_encoding_of_the_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = decode_hash(MD5, HashEncoding::Hex).unwrap();
+        let store = MemoryStore::default();
+        let encodings = [HashEncoding::Hex, HashEncoding::Base64, HashEncoding::Base32];
+        for (i, encoding) in encodings.into_iter().enumerate() {
+            let mut cache = HashCache::new(Box::new(store.clone()), dir.path().to_path_buf(), Algorithm::Md5, encoding);
+            let name = format!("{}.txt", i);
+            cache.append(&entry(&name, Algorithm::Md5, &encode_hash(&raw, encoding))).unwrap();
+        }
+        // Hex stays untagged, the others record their encoding
+        let mut stored: Vec<String> = store.entries.lock().unwrap().values().cloned().collect();
+        stored.sort();
+        assert_eq!(stored[0], MD5);
+        assert!(stored[1].starts_with("base32:") && stored[2].starts_with("base64:"), "{:?}", stored);
+
+        for encoding in encodings {
+            let new_store = Box::new(MemoryStore::default());
+            let mut cache = HashCache::new(new_store, dir.path().to_path_buf(), Algorithm::Md5, encoding);
+            assert_eq!(cache.load(&store, dir.path()).unwrap(), 3);
+            for name in ["0.txt", "1.txt", "2.txt"] {
+                let hash = cache.get(name, 4, 1, Algorithm::Md5).unwrap();
+                assert_eq!(hash, encode_hash(&raw, encoding), "{} as {:?}", name, encoding);
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_csv_load_matches_a_sequential_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("duplicates.hashes.csv");
+        let mut store = CsvStore::new(path.clone());
+        let entries: Vec<HashEntry> = (0..150_000)
+            .map(|i| HashEntry {
+                // Quoted fields with line breaks must not be cut between chunks
+                path: match i % 997 {
+                    0 => format!("odd\nname; \"{}\"\n.txt", i),
+                    _ => format!("photos/{:03}/IMG_{:06}.jpg", i % 500, i),
+                },
+                size: i,
+                time: i * 7,
+                algo: Algorithm::Md5,
+                hash: format!("{:032x}", i),
+            })
+            .collect();
+        store.append(&entries).unwrap();
+        // Later rows win, unparsable ones are skipped
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut file, b"not;a;row\n").unwrap();
+        let rewritten: Vec<HashEntry> = entries[..100]
+            .iter()
+            .map(|e| HashEntry {
+                path: e.path.clone(),
+                hash: MD5.to_string(),
+                ..*e
+            })
+            .collect();
+        store.append(&rewritten).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() > 2 * CSV_CHUNK_BYTES as u64);
+
+        let mut sequential = HashMap::new();
+        let mut rdr = csv::ReaderBuilder::new().delimiter(b';').from_path(&path).unwrap();
+        for e in rdr.deserialize::<HashEntry>().flatten() {
+            sequential.insert(CacheKey { path: e.path, size: e.size, time: e.time, algo: e.algo }, e.hash);
+        }
+        assert_eq!(sequential.len(), entries.len());
+
+        let parallel = store.load().unwrap();
+        assert_eq!(parallel, sequential);
+        let first = CacheKey { path: entries[0].path.clone(), size: 0, time: 0, algo: Algorithm::Md5 };
+        assert_eq!(parallel[&first], MD5);
+    }
+}
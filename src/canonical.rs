@@ -0,0 +1,114 @@
+use crate::error::{DedupError, IoResultExt, Result};
+use crate::hashing::{digest, HashOptions};
+use crate::models::{Algorithm, FileInfo};
+use quick_xml::events::attributes::Attribute;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::name::QName;
+use quick_xml::{Reader, Writer};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+/// Prefix of the group keys of documents matched by [`canonical_hash`], which
+/// are not content hashes
+pub const CANONICAL_KEY_PREFIX: &str = "canonical:";
+
+/// Structured text formats [`canonical_form`] understands
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum StructuredKind {
+    Json,
+    Xml,
+}
+
+/// The format of a discovered file, by extension. Archive entries are always
+/// compared as they are.
+pub fn structured_kind(file: &FileInfo) -> Option<StructuredKind> {
+    if file.archive_entry.is_some() {
+        return None;
+    }
+    structured_path_kind(&file.path)
+}
+
+/// The format of a file on disk, by the extension of `path`
+pub fn structured_path_kind(path: &Path) -> Option<StructuredKind> {
+    let extension = path.extension()?.to_string_lossy().to_lowercase();
+    match extension.as_str() {
+        "json" => Some(StructuredKind::Json),
+        "xml" => Some(StructuredKind::Xml),
+        _ => None,
+    }
+}
+
+fn malformed(path: &Path, source: impl std::error::Error + Send + Sync + 'static) -> DedupError {
+    DedupError::Structured {
+        path: path.to_path_buf(),
+        source: Box::new(source),
+    }
+}
+
+/// JSON with sorted keys and no whitespace
+fn canonical_json(path: &Path, reader: impl BufRead) -> Result<Vec<u8>> {
+    // Without serde_json's preserve_order, objects are sorted maps
+    let value: serde_json::Value = serde_json::from_reader(reader).map_err(|e| malformed(path, e))?;
+    serde_json::to_vec(&value).map_err(|e| malformed(path, e))
+}
+
+/// XML with sorted attributes and surrounding whitespace trimmed from text
+fn canonical_xml(path: &Path, reader: impl BufRead) -> Result<Vec<u8>> {
+    let mut reader = Reader::from_reader(reader);
+    reader.config_mut().trim_text(true);
+    let mut writer = Writer::new(Vec::new());
+    let mut buf = Vec::new();
+    loop {
+        let event = match reader.read_event_into(&mut buf).map_err(|e| malformed(path, e))? {
+            Event::Eof => break,
+            Event::Start(start) => Event::Start(sorted_attributes(path, &start)?),
+            Event::Empty(start) => Event::Empty(sorted_attributes(path, &start)?),
+            event => event,
+        };
+        writer.write_event(event).at(path)?;
+        buf.clear();
+    }
+    Ok(writer.into_inner())
+}
+
+fn sorted_attributes(path: &Path, start: &BytesStart) -> Result<BytesStart<'static>> {
+    let mut attributes = start
+        .attributes()
+        .map(|a| a.map(|a| (a.key.into_inner().to_string(), a.value.into_owned())))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| malformed(path, e))?;
+    attributes.sort();
+    let mut sorted = BytesStart::new(start.name().into_inner().to_string());
+    for (key, value) in &attributes {
+        // Values are kept raw, as they were escaped in the document
+        sorted.push_attribute(Attribute {
+            key: QName(key),
+            value: Cow::Borrowed(value),
+        });
+    }
+    Ok(sorted)
+}
+
+/// Parse a document and serialize it again canonically, so documents that only
+/// differ in key or attribute order and whitespace come out the same
+pub fn canonical_form(path: &Path, kind: StructuredKind) -> Result<Vec<u8>> {
+    canonicalize(path, File::open(path).at(path)?, kind)
+}
+
+fn canonicalize(path: &Path, reader: impl Read, kind: StructuredKind) -> Result<Vec<u8>> {
+    let reader = BufReader::new(reader);
+    match kind {
+        StructuredKind::Json => canonical_json(path, reader),
+        StructuredKind::Xml => canonical_xml(path, reader),
+    }
+}
+
+/// Hash the canonical form of a JSON or XML file. Fails with
+/// [`DedupError::Structured`] if it doesn't parse.
+pub fn canonical_hash(file: &FileInfo, kind: StructuredKind, algo: Algorithm, options: &HashOptions) -> Result<String> {
+    let reader = options.throttle(File::open(&file.path).at(&file.path)?);
+    let canonical = canonicalize(&file.path, reader, kind)?;
+    digest(&mut canonical.as_slice(), algo, options.encoding)
+}
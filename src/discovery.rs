@@ -0,0 +1,471 @@
+use crate::archive::{is_archive, list_entries};
+use crate::error::{DedupError, IoResultExt, Result};
+use crate::models::{FileInfo, TimeSource};
+use crate::platform::{
+    file_identity, get_allocated_size, get_change_time, get_device_id, get_file_index, is_reparse_point,
+};
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use walkdir::{DirEntry, FilterEntry, WalkDir};
+
+/// Default for [`DiscoverOptions::max_depth`], far deeper than any real tree
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+/// Options controlling which entries the discovery phase yields
+#[derive(Debug, Clone)]
+pub struct DiscoverOptions {
+    pub recursive: bool,
+    /// Deepest level below the root that is listed when recursive
+    pub max_depth: usize,
+    /// Enter symlinked directories
+    pub follow_symlinks: bool,
+    /// File or directory names to skip entirely
+    pub ignore: HashSet<String>,
+    /// Directories to skip entirely, by full path
+    pub exclude_dirs: Vec<PathBuf>,
+    pub hash_symlink_targets: bool,
+    /// Also yield the entries of zip/tar archives as virtual files
+    pub scan_archives: bool,
+    /// Which timestamp fills [`FileInfo::keep_time`]
+    pub time_source: TimeSource,
+}
+
+impl Default for DiscoverOptions {
+    fn default() -> Self {
+        Self {
+            recursive: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+            follow_symlinks: false,
+            ignore: HashSet::new(),
+            exclude_dirs: Vec::new(),
+            hash_symlink_targets: false,
+            scan_archives: false,
+            time_source: TimeSource::default(),
+        }
+    }
+}
+
+/// Symlinks resolving through more hops than this are reported as chains
+const MAX_LINK_HOPS: usize = 1;
+/// Give up following a chain after this many hops (mirrors Linux's SYMLOOP_MAX)
+const LINK_HOP_LIMIT: usize = 40;
+
+type EntryFilter = Box<dyn FnMut(&DirEntry) -> bool + Send>;
+type Walker = FilterEntry<walkdir::IntoIter, EntryFilter>;
+
+/// Iterator over the regular files below a root, see [`discover`]
+pub struct Discover {
+    root: PathBuf,
+    walker: Option<Walker>,
+    /// Directories still to be walked one level deep, see [`discover_in`]
+    queue: VecDeque<PathBuf>,
+    queued: bool,
+    ignore: HashSet<String>,
+    exclude_dirs: Vec<PathBuf>,
+    /// Deepest level below `root` a file is discovered at
+    max_depth: usize,
+    follow_symlinks: bool,
+    hash_symlink_targets: bool,
+    scan_archives: bool,
+    time_source: TimeSource,
+    pending: VecDeque<Result<FileInfo>>,
+    folder_count: usize,
+    broken_links: Vec<PathBuf>,
+    link_chains: Vec<(PathBuf, usize)>,
+    /// Entries skipped because their name is in `ignore`, shared with the walker's filter
+    ignored: Arc<Mutex<Vec<PathBuf>>>,
+    /// Directories entered so far, by [`file_identity`]
+    visited: Arc<Mutex<HashSet<(u64, u64)>>>,
+    /// Directories not entered because they were already walked, shared with the walker's filter
+    cycles: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+/// Walk `root` and yield a [`FileInfo`] for every regular file (and, if
+/// enabled, every symlink to one). Nothing is hashed or filtered by size here,
+/// so callers can apply their own filters before the rest of the pipeline.
+///
+/// ```
+/// use duplicates::{discover, DiscoverOptions};
+/// # let dir = tempfile::tempdir().unwrap();
+/// # std::fs::write(dir.path().join("photo.jpg"), "jpeg").unwrap();
+/// # std::fs::write(dir.path().join("notes.txt"), "text").unwrap();
+/// # let root = dir.path().to_path_buf();
+///
+/// let options = DiscoverOptions { recursive: true, ..Default::default() };
+/// // The iterator can be handed to another thread
+/// let photos = std::thread::spawn(move || {
+///     discover(&root, &options)
+///         .filter_map(Result::ok)
+///         .filter(|f| f.path.extension().is_some_and(|e| e == "jpg"))
+///         .map(|f| f.rel_path)
+///         .collect::<Vec<_>>()
+/// });
+/// assert_eq!(photos.join().unwrap(), ["photo.jpg"]);
+/// ```
+pub fn discover(root: &Path, opts: &DiscoverOptions) -> Discover {
+    let mut discovery = discover_in(root, opts, Vec::new(), Vec::new(), 0);
+    discovery.queued = false;
+    discovery.walker = Some(discovery.walk(root, discovery.max_depth));
+    discovery
+}
+
+/// Like [`discover`], but only walk `dirs` (each one level deep, their
+/// subdirectories are not entered) and yield the already `known` files first.
+/// `known_folders` directories are counted as walked without being visited.
+/// Cycles among `dirs` are the caller's to avoid, see [`crate::incremental::plan`].
+pub fn discover_in(
+    root: &Path,
+    opts: &DiscoverOptions,
+    dirs: Vec<PathBuf>,
+    known: Vec<FileInfo>,
+    known_folders: usize,
+) -> Discover {
+    Discover {
+        root: root.to_path_buf(),
+        walker: None,
+        queue: dirs.into(),
+        queued: true,
+        ignore: opts.ignore.clone(),
+        exclude_dirs: opts.exclude_dirs.clone(),
+        max_depth: if opts.recursive { opts.max_depth } else { 1 },
+        follow_symlinks: opts.follow_symlinks,
+        hash_symlink_targets: opts.hash_symlink_targets,
+        scan_archives: opts.scan_archives,
+        time_source: opts.time_source,
+        pending: known.into_iter().map(Ok).collect(),
+        folder_count: known_folders,
+        broken_links: Vec::new(),
+        link_chains: Vec::new(),
+        ignored: Arc::new(Mutex::new(Vec::new())),
+        visited: Arc::new(Mutex::new(HashSet::new())),
+        cycles: Arc::new(Mutex::new(Vec::new())),
+    }
+}
+
+impl Discover {
+    /// Walk `dir` down to `max_depth`, skipping ignored names, excluded
+    /// directories and, unless queued, directories that were already entered
+    fn walk(&self, dir: &Path, max_depth: usize) -> Walker {
+        let ignore = self.ignore.clone();
+        let exclude_dirs = self.exclude_dirs.clone();
+        let ignored_by_filter = self.ignored.clone();
+        let visited = self.visited.clone();
+        let cycles = self.cycles.clone();
+        let track_visited = !self.queued;
+        let filter: EntryFilter = Box::new(move |e: &DirEntry| {
+            if ignore.contains(e.file_name().to_string_lossy().as_ref()) {
+                ignored_by_filter.lock().unwrap().push(e.path().to_path_buf());
+                return false;
+            }
+            if exclude_dirs.iter().any(|d| d.as_path() == e.path()) {
+                return false;
+            }
+            // A bind mount or followed link can lead back into a directory walked before
+            if track_visited && e.file_type().is_dir() {
+                if let Some(identity) = file_identity(e.path()) {
+                    if !visited.lock().unwrap().insert(identity) {
+                        cycles.lock().unwrap().push(e.path().to_path_buf());
+                        return false;
+                    }
+                }
+            }
+            true
+        });
+        WalkDir::new(dir)
+            .max_depth(max_depth)
+            .follow_links(self.follow_symlinks)
+            .into_iter()
+            .filter_entry(filter)
+    }
+
+    /// Number of directories walked so far (including the root)
+    pub fn folder_count(&self) -> usize {
+        self.folder_count
+    }
+
+    /// Symlinks whose target does not exist (or that loop back on themselves)
+    pub fn broken_links(&self) -> &[PathBuf] {
+        &self.broken_links
+    }
+
+    /// Symlinks pointing at other symlinks, with the number of hops to the real file
+    pub fn link_chains(&self) -> &[(PathBuf, usize)] {
+        &self.link_chains
+    }
+
+    /// Directories that were reached a second time and not entered again,
+    /// through a symlink or bind mount loop
+    pub fn cycles(&self) -> Vec<PathBuf> {
+        self.cycles.lock().unwrap().clone()
+    }
+
+    /// Entries skipped by the ignore list so far. Nothing below them is read
+    /// until [`Ignored::files`] is called.
+    pub fn ignored(&self) -> Ignored {
+        Ignored {
+            root: self.root.clone(),
+            max_depth: self.max_depth,
+            time_source: self.time_source,
+            paths: self.ignored.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Files and directories discovery skipped by name, see [`Discover::ignored`]
+#[derive(Debug, Clone)]
+pub struct Ignored {
+    root: PathBuf,
+    max_depth: usize,
+    time_source: TimeSource,
+    paths: Vec<PathBuf>,
+}
+
+impl Ignored {
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    /// Regular files among the ignored entries and inside ignored directories,
+    /// down to the depth the walk would have reached, that `wanted` accepts by
+    /// path and size. Only those are read in full.
+    pub fn files(&self, wanted: impl Fn(&Path, u64) -> bool) -> Vec<FileInfo> {
+        self.paths
+            .iter()
+            .flat_map(|p| {
+                let depth = p.strip_prefix(&self.root).map_or(0, |rel| rel.components().count());
+                WalkDir::new(p).max_depth(self.max_depth.saturating_sub(depth)).into_iter().flatten()
+            })
+            .filter(|e| e.file_type().is_file())
+            .filter(|e| e.metadata().is_ok_and(|m| wanted(e.path(), m.len())))
+            .filter_map(|e| read_file_info(&self.root, e.path(), false, self.time_source).ok().flatten())
+            .collect()
+    }
+}
+
+/// Count how many symlinks have to be followed from `path` to reach a non-link
+fn link_hops(path: &Path) -> usize {
+    let mut hops = 0;
+    let mut current = path.to_path_buf();
+    while hops < LINK_HOP_LIMIT {
+        match fs::read_link(&current) {
+            Ok(target) => {
+                current = current.parent().map(|p| p.join(&target)).unwrap_or(target);
+                hops += 1;
+            }
+            Err(_) => break,
+        }
+    }
+    hops
+}
+
+impl Iterator for Discover {
+    type Item = Result<FileInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(item) = self.pending.pop_front() {
+            return Some(item);
+        }
+        loop {
+            let Some(walker) = &mut self.walker else {
+                let dir = self.queue.pop_front()?;
+                self.walker = Some(self.walk(&dir, 1));
+                continue;
+            };
+            let entry = match walker.next() {
+                Some(Ok(e)) => e,
+                Some(Err(e)) => {
+                    // Reported by walkdir itself for a followed link back to an ancestor
+                    if let Some(path) = e.loop_ancestor().and(e.path()) {
+                        self.cycles.lock().unwrap().push(path.to_path_buf());
+                        continue;
+                    }
+                    // Following links, walkdir can't stat a dangling one
+                    if let Some(path) = e.path().filter(|p| p.is_symlink() && fs::metadata(p).is_err()) {
+                        self.broken_links.push(path.to_path_buf());
+                        continue;
+                    }
+                    return Some(Err(e.into()));
+                }
+                None => {
+                    self.walker = None;
+                    continue;
+                }
+            };
+            if entry.file_type().is_dir() {
+                // A queued directory's subdirectories are queued (or known) themselves
+                if entry.depth() == 0 || !self.queued {
+                    self.folder_count += 1;
+                }
+                continue;
+            }
+            let is_link = entry.path_is_symlink();
+            if is_link {
+                if fs::metadata(entry.path()).is_err() {
+                    self.broken_links.push(entry.path().to_path_buf());
+                    continue;
+                }
+                let hops = link_hops(entry.path());
+                if hops > MAX_LINK_HOPS {
+                    self.link_chains.push((entry.path().to_path_buf(), hops));
+                }
+                if !self.hash_symlink_targets {
+                    continue;
+                }
+            } else if !entry.file_type().is_file() {
+                continue;
+            }
+
+            match read_file_info(&self.root, entry.path(), is_link, self.time_source) {
+                Ok(Some(info)) => {
+                    if self.scan_archives && info.link_target.is_none() && is_archive(&info.path) {
+                        match list_entries(&info) {
+                            Ok(entries) => self.pending.extend(entries.into_iter().map(Ok)),
+                            Err(e) => self.pending.push_back(Err(e)),
+                        }
+                    }
+                    return Some(Ok(info));
+                }
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Collect the metadata for a single file below `root`. Returns `None` for
+/// entries that should not be processed (reparse points, links to non-files).
+pub fn read_file_info(root: &Path, path: &Path, is_link: bool, time_source: TimeSource) -> Result<Option<FileInfo>> {
+    // Follows symlinks, so links are sized and timed by their target
+    let metadata = fs::metadata(path).at(path)?;
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+
+    let link_target = if is_link {
+        fs::read_link(path).ok()
+    } else {
+        // Skip all reparse points (symlinks, junctions, hardlinks, etc.)
+        if is_reparse_point(path) {
+            return Ok(None);
+        }
+        None
+    };
+
+    let rel_path = path
+        .strip_prefix(root)
+        .map_err(|_| DedupError::OutsideRoot {
+            path: path.to_path_buf(),
+            root: root.to_path_buf(),
+        })?
+        .to_string_lossy()
+        .into_owned();
+    let nanos = |t: std::io::Result<SystemTime>| {
+        t.ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos() as u64)
+    };
+    let mtime = nanos(metadata.modified()).unwrap_or(0);
+    let keep_time = match time_source {
+        TimeSource::Mtime => Some(mtime),
+        TimeSource::Ctime => get_change_time(path).unwrap_or(None),
+        TimeSource::Atime => nanos(metadata.accessed()),
+        TimeSource::Btime => nanos(metadata.created()),
+    };
+
+    // A link shares its target's inode; keep it out of the hardlink filter.
+    // Its device is where a replacement link would be created.
+    let (inode, dev) = if is_link {
+        (None, path.parent().and_then(|p| get_device_id(p).unwrap_or(None)))
+    } else {
+        (get_file_index(path).unwrap_or(None), get_device_id(path).unwrap_or(None))
+    };
+
+    Ok(Some(FileInfo {
+        path: path.to_path_buf(),
+        key_path: rel_path.clone(),
+        rel_path,
+        size: metadata.len(),
+        mtime,
+        keep_time,
+        inode,
+        dev,
+        link_target,
+        archive_entry: None,
+        allocated: get_allocated_size(path).unwrap_or(None),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rel_paths(discovery: &mut Discover) -> Vec<String> {
+        let mut paths: Vec<String> = discovery.by_ref().map(|f| f.unwrap().rel_path).collect();
+        paths.sort();
+        paths
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn broken_symlinks_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file.txt"), "content").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("missing.txt"), dir.path().join("dangling.txt")).unwrap();
+
+        for hash_symlink_targets in [false, true] {
+            let options = DiscoverOptions { hash_symlink_targets, ..Default::default() };
+            let mut discovery = discover(dir.path(), &options);
+            assert_eq!(rel_paths(&mut discovery), ["file.txt"]);
+            assert_eq!(discovery.broken_links(), [dir.path().join("dangling.txt")]);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sparse_files_are_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let sparse = dir.path().join("sparse.img");
+        fs::File::create(&sparse).unwrap().set_len(64 * 1024 * 1024).unwrap();
+        let dense = dir.path().join("dense.bin");
+        fs::write(&dense, vec![1u8; 64 * 1024]).unwrap();
+
+        let sparse = read_file_info(dir.path(), &sparse, false, TimeSource::Mtime).unwrap().unwrap();
+        assert!(sparse.is_sparse(), "{:?} allocated", sparse.allocated);
+        let dense = read_file_info(dir.path(), &dense, false, TimeSource::Mtime).unwrap().unwrap();
+        assert!(!dense.is_sparse(), "{:?} allocated", dense.allocated);
+    }
+
+    #[test]
+    fn ignored_entries_are_only_read_when_asked_and_wanted() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = [("kept.txt", "1234"), ("skip/a.txt", "1234"), ("skip/b.txt", "123456"), ("skip.log", "1234")];
+        for (rel, contents) in layout {
+            let path = dir.path().join(rel);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        let options = DiscoverOptions {
+            recursive: true,
+            ignore: ["skip".to_string(), "skip.log".to_string()].into(),
+            ..Default::default()
+        };
+        let mut discovery = discover(dir.path(), &options);
+        assert_eq!(rel_paths(&mut discovery), ["kept.txt"]);
+        let ignored = discovery.ignored();
+        assert!(!ignored.is_empty());
+
+        // Nothing below the ignored directory was listed yet
+        fs::write(dir.path().join("skip/late.txt"), "1234").unwrap();
+        let mut files: Vec<String> = ignored
+            .files(|path, size| size == 4 && path.extension().is_some_and(|e| e == "txt"))
+            .into_iter()
+            .map(|f| f.rel_path)
+            .collect();
+        files.sort();
+        let expected = ["a.txt", "late.txt"].map(|name| Path::new("skip").join(name).to_string_lossy().into_owned());
+        assert_eq!(files, expected);
+    }
+}
@@ -0,0 +1,131 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Everything the library API can fail with
+#[derive(Debug, Error)]
+pub enum DedupError {
+    /// An I/O operation on a specific path failed
+    #[error("{}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// An I/O error on an already open stream
+    #[error(transparent)]
+    Stream(#[from] std::io::Error),
+    /// Walking the directory tree failed
+    #[error(transparent)]
+    Walk(#[from] walkdir::Error),
+    /// The operation isn't available on this platform
+    #[error("{0} not supported on this platform")]
+    Unsupported(&'static str),
+    /// Symlinks can't be created without elevated rights
+    #[error(
+        "Cannot create symlinks in {} ({source}). Enable Windows Developer Mode or run as administrator, \
+         or use --mode hardlink/delete instead.",
+        dir.display()
+    )]
+    SymlinkPrivilege {
+        dir: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// A hash cache file is unreadable or corrupt, or can't be written
+    #[error("Hash cache {}: {source}", path.display())]
+    Cache {
+        path: PathBuf,
+        #[source]
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// The trash manifest is unreadable or can't be written
+    #[error("Trash manifest {}: {source}", path.display())]
+    Manifest {
+        path: PathBuf,
+        #[source]
+        source: csv::Error,
+    },
+    /// An archive is unsupported, corrupt or lacks the requested entry
+    #[error("Archive {}: {source}", path.display())]
+    Archive {
+        path: PathBuf,
+        #[source]
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// The incremental scan state can't be written
+    #[error("Scan state {}: {source}", path.display())]
+    State {
+        path: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+    /// The keep rules file is unreadable, malformed or has an invalid glob
+    #[error("Rules file {}: {source}", path.display())]
+    Rules {
+        path: PathBuf,
+        #[source]
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// The duplicate report can't be written
+    #[error("Report {}: {source}", path.display())]
+    Report {
+        path: PathBuf,
+        #[source]
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// A JSON or XML document doesn't parse, so it has no canonical form
+    #[error("Malformed document {}: {source}", path.display())]
+    Structured {
+        path: PathBuf,
+        #[source]
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// A discovered path doesn't lie below the scan root
+    #[error("{} is outside the scan root {}", path.display(), root.display())]
+    OutsideRoot { path: PathBuf, root: PathBuf },
+}
+
+pub type Result<T> = std::result::Result<T, DedupError>;
+
+/// Attach the path an I/O operation was working on to its error
+pub(crate) trait IoResultExt<T> {
+    fn at(self, path: &Path) -> Result<T>;
+}
+
+impl<T> IoResultExt<T> for std::io::Result<T> {
+    fn at(self, path: &Path) -> Result<T> {
+        self.map_err(|source| DedupError::Io {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashing::{calculate_hash, HashOptions};
+    use crate::models::Algorithm;
+    use crate::rules::Rules;
+
+    #[test]
+    fn failures_come_back_as_their_variant() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.txt");
+        match calculate_hash(&missing, Algorithm::Md5, &HashOptions::default()) {
+            Err(DedupError::Io { path, source }) => {
+                assert_eq!(path, missing);
+                assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+            }
+            other => panic!("expected an Io error, got {:?}", other),
+        }
+
+        let rules = dir.path().join("rules.json");
+        std::fs::write(&rules, r#"[{"glob": "[", "keep": "latest"}]"#).unwrap();
+        match Rules::load(&rules) {
+            Err(DedupError::Rules { path, .. }) => assert_eq!(path, rules),
+            other => panic!("expected a Rules error, got {:?}", other),
+        }
+    }
+}
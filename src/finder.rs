@@ -0,0 +1,768 @@
+use crate::cache::{is_cache_sidecar, open_store, parse_cache_file_name, CacheKey, CacheStore, HashCache};
+use crate::discovery::{discover, DiscoverOptions};
+use crate::error::Result;
+use crate::hashing::{hash_entries, hash_file, sampled_hash, validate_hash, HashOptions};
+use crate::models::{Algorithm, FileInfo, HashEntry};
+use crate::platform::file_identity;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// Stages of [`find_duplicates`], in the order they run
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Phase {
+    Discovering,
+    LoadingCache,
+    Hashing,
+    Grouping,
+}
+
+/// Progress reported to the callback of [`find_duplicates`] and [`hash_files`]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ProgressEvent {
+    /// A phase began. Totals are only known upfront for hashing and are 0 otherwise.
+    PhaseStarted { phase: Phase, total_files: usize, total_bytes: u64 },
+    /// Running totals of files processed and bytes hashed within the current phase
+    Progress { phase: Phase, files: usize, bytes: u64 },
+}
+
+/// What [`find_duplicates`] scans and how it groups
+#[derive(Debug, Clone)]
+pub struct FindConfig {
+    pub root: PathBuf,
+    pub discover: DiscoverOptions,
+    pub algorithm: Algorithm,
+    pub min_size: u64,
+    pub max_size: u64,
+    pub hashing: HashOptions,
+    /// Cache file new hashes are written to. Caches found while scanning are read either way.
+    pub cache_path: Option<PathBuf>,
+}
+
+impl FindConfig {
+    /// Recursively scan `root` for files with equal MD5 hashes, without writing a cache
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            discover: DiscoverOptions {
+                recursive: true,
+                ..Default::default()
+            },
+            algorithm: Algorithm::Md5,
+            min_size: 0,
+            max_size: u64::MAX,
+            hashing: HashOptions::default(),
+            cache_path: None,
+        }
+    }
+}
+
+/// Result of [`find_copies`]
+#[derive(Clone, Default)]
+pub struct Copies {
+    /// Files below the other root with their duplicates below the kept root
+    pub copies: Vec<(FileInfo, Vec<FileInfo>)>,
+    /// Files below the other root that *are* a file below the kept root, reached
+    /// through a hardlink or a second mount of the filesystem, with that file.
+    /// Acting on them would destroy the original.
+    pub aliases: Vec<(FileInfo, FileInfo)>,
+    /// Files discovered below both roots
+    pub files_scanned: usize,
+    /// Files that couldn't be read during discovery or hashing
+    pub errors: usize,
+}
+
+/// Store for runs without a cache file: starts empty and forgets every write
+struct NoStore;
+
+impl CacheStore for NoStore {
+    fn load(&self) -> Result<HashMap<CacheKey, String>> {
+        Ok(HashMap::new())
+    }
+
+    fn append(&mut self, _entries: &[HashEntry]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Whether `full_hash_max` puts a file in sampled instead of full hashing
+pub fn is_sampled(f: &FileInfo, full_hash_max: Option<u64>) -> bool {
+    full_hash_max.is_some_and(|max| f.size > max)
+}
+
+/// Hash a file the way grouping does, sampled above `options.full_hash_max`
+pub fn content_hash(f: &FileInfo, algo: Algorithm, options: &HashOptions) -> Result<String> {
+    if is_sampled(f, options.full_hash_max) {
+        sampled_hash(f, algo, options)
+    } else {
+        hash_file(f, algo, options)
+    }
+}
+
+/// Hash `files` in parallel like [`content_hash`] and pass each result to `done`
+/// as soon as it is known. The entries of an archive are hashed together in a
+/// single pass over it. Once `interrupted` is set the remaining files are skipped.
+pub fn hash_each(
+    files: Vec<FileInfo>,
+    algo: Algorithm,
+    options: &HashOptions,
+    interrupted: &AtomicBool,
+    done: impl Fn(FileInfo, Result<String>) + Sync,
+) {
+    let mut loose = Vec::new();
+    let mut archives: HashMap<PathBuf, Vec<FileInfo>> = HashMap::new();
+    for f in files {
+        match f.archive_entry {
+            Some(_) => archives.entry(f.path.clone()).or_default().push(f),
+            None => loose.push(f),
+        }
+    }
+    let archives: Vec<Vec<FileInfo>> = archives.into_values().collect();
+    rayon::join(
+        || {
+            loose.into_par_iter().for_each(|f| {
+                if !interrupted.load(Ordering::SeqCst) {
+                    let hash = content_hash(&f, algo, options);
+                    done(f, hash);
+                }
+            })
+        },
+        || {
+            archives.into_par_iter().for_each(|entries| {
+                if !interrupted.load(Ordering::SeqCst) {
+                    for (f, hash) in hash_entries(entries, algo, options) {
+                        done(f, hash);
+                    }
+                }
+            })
+        },
+    );
+}
+
+/// Whether two discovered files are one file on disk, like hardlinks or the
+/// same file seen through two mounts of a filesystem
+pub fn same_file(a: &FileInfo, b: &FileInfo) -> bool {
+    matches!((a.inode, b.inode), (Some(x), Some(y)) if x != 0 && x == y) && a.dev.is_some() && a.dev == b.dev
+}
+
+/// Whether two paths are one directory, e.g. because one is a bind mount of the other
+pub fn same_directory(a: &Path, b: &Path) -> bool {
+    matches!((file_identity(a), file_identity(b)), (Some(x), Some(y)) if x == y)
+}
+
+/// Grouping key of the algorithms that don't read content, `None` for hashing ones
+pub fn metadata_key(f: &FileInfo, algo: Algorithm) -> Option<String> {
+    match algo {
+        Algorithm::Name => Some(f.path.file_name().unwrap_or_default().to_string_lossy().into_owned()),
+        Algorithm::Size => Some(f.size.to_string()),
+        Algorithm::Path => Some(f.key_path.clone()),
+        _ => None,
+    }
+}
+
+/// Split `files` into cache hits (with their hash) and files that still need hashing.
+/// Hits are taken out of `cache`, each file is only looked up once. Sampled
+/// hashes must never be mixed with full ones, so those files always need hashing.
+pub fn split_cached(
+    files: Vec<FileInfo>,
+    cache: &mut HashCache,
+    algo: Algorithm,
+    full_hash_max: Option<u64>,
+) -> (Vec<(FileInfo, String)>, Vec<FileInfo>) {
+    let mut cached_files = Vec::new();
+    let mut files_to_hash = Vec::new();
+    for f in files {
+        let cached = if is_sampled(&f, full_hash_max) {
+            None
+        } else {
+            cache.take(&f.rel_path, f.size, f.mtime, algo)
+        };
+        match cached {
+            Some(hash) => cached_files.push((f, hash)),
+            None => files_to_hash.push(f),
+        }
+    }
+    (cached_files, files_to_hash)
+}
+
+/// Hash `files` in parallel. The workers stream their results to a single
+/// writer thread that appends every full hash to `cache`, and each result to
+/// `live` as `hash;size;path` rows, as soon as it is known. Unreadable files
+/// are dropped. Once `interrupted` is set the remaining files are skipped,
+/// everything hashed so far is already written. Fails only if `live` can't be
+/// written, the cache is best effort.
+pub fn hash_files(
+    files: Vec<FileInfo>,
+    algo: Algorithm,
+    options: &HashOptions,
+    cache: &mut HashCache,
+    live: Option<&mut (dyn Write + Send)>,
+    interrupted: &AtomicBool,
+    progress: &(dyn Fn(ProgressEvent) + Sync),
+) -> Result<Vec<(FileInfo, String)>> {
+    progress(ProgressEvent::PhaseStarted {
+        phase: Phase::Hashing,
+        total_files: files.len(),
+        total_bytes: files.iter().map(|f| f.size).sum(),
+    });
+    let files_done = AtomicUsize::new(0);
+    let bytes_done = AtomicU64::new(0);
+    let report = |f: &FileInfo| {
+        progress(ProgressEvent::Progress {
+            phase: Phase::Hashing,
+            files: files_done.fetch_add(1, Ordering::SeqCst) + 1,
+            bytes: bytes_done.fetch_add(f.size, Ordering::SeqCst) + f.size,
+        });
+    };
+
+    let (sender, receiver) = mpsc::channel::<(FileInfo, String)>();
+    thread::scope(|scope| {
+        let writer = scope.spawn(move || -> Result<Vec<(FileInfo, String)>> {
+            let mut live = live.map(|out| csv::WriterBuilder::new().delimiter(b';').has_headers(false).from_writer(out));
+            let mut live_error = None;
+            let mut results = Vec::new();
+            while let Ok(first) = receiver.recv() {
+                // Whatever queued up during the last write goes out in one batch
+                let batch: Vec<(FileInfo, String)> = std::iter::once(first).chain(receiver.try_iter()).collect();
+                let entries: Vec<HashEntry> = batch
+                    .iter()
+                    .filter(|(f, _)| !is_sampled(f, options.full_hash_max))
+                    .map(|(f, hash)| HashEntry {
+                        path: f.rel_path.clone(),
+                        size: f.size,
+                        time: f.mtime,
+                        algo,
+                        hash: hash.clone(),
+                    })
+                    .collect();
+                let _ = cache.append_all(&entries);
+                if let (Some(out), None) = (live.as_mut(), &live_error) {
+                    let written: csv::Result<()> = batch
+                        .iter()
+                        .try_for_each(|(f, hash)| out.write_record([hash.as_str(), &f.size.to_string(), &f.rel_path]))
+                        .and_then(|()| Ok(out.flush()?));
+                    live_error = written.err();
+                }
+                results.extend(batch);
+            }
+            match live_error {
+                Some(e) => Err(std::io::Error::from(e).into()),
+                None => Ok(results),
+            }
+        });
+
+        hash_each(files, algo, options, interrupted, |f, hash| {
+            let hash = hash.unwrap_or_default();
+            report(&f);
+            if validate_hash(&hash, algo, options.encoding) {
+                let _ = sender.send((f, hash));
+            }
+        });
+        drop(sender);
+        writer.join().expect("hash writer thread panicked")
+    })
+}
+
+/// Discovered files with the hash caches among them set apart, see [`collect`]
+#[derive(Default)]
+pub struct Collected {
+    pub files: Vec<FileInfo>,
+    /// Cache files shared by all algorithms or written for the one collected for
+    pub cache_files: Vec<PathBuf>,
+    /// Entries discovery couldn't read
+    pub unreadable: usize,
+}
+
+/// Take the files of `discovered`, setting the hash caches of `algo` aside and
+/// dropping every other cache file and sidecar: they are the tool's own and
+/// never duplicates of anything.
+pub fn collect<E>(
+    discovered: impl IntoIterator<Item = std::result::Result<FileInfo, E>>,
+    algo: Algorithm,
+    progress: &(dyn Fn(ProgressEvent) + Sync),
+) -> Collected {
+    progress(ProgressEvent::PhaseStarted { phase: Phase::Discovering, total_files: 0, total_bytes: 0 });
+    let mut collected = Collected::default();
+    for file in discovered {
+        let Ok(file) = file else {
+            collected.unreadable += 1;
+            continue;
+        };
+        let name = file.path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        if let Some(cache_algo) = parse_cache_file_name(&name) {
+            if cache_algo.is_none() || cache_algo == Some(algo) {
+                collected.cache_files.push(file.path);
+            }
+            continue;
+        }
+        if is_cache_sidecar(&name) {
+            continue;
+        }
+        collected.files.push(file);
+        progress(ProgressEvent::Progress { phase: Phase::Discovering, files: collected.files.len(), bytes: 0 });
+    }
+    collected
+}
+
+/// Attach `cache_files` to `cache`, holding only the entries `wanted` accepts
+/// of those loaded up front. Unreadable caches are skipped. Returns the hashes
+/// loaded, SQLite caches are queried per file instead.
+pub fn attach_caches(
+    cache: &mut HashCache,
+    cache_files: &[PathBuf],
+    root: &Path,
+    wanted: impl Fn(&CacheKey) -> bool,
+    progress: &(dyn Fn(ProgressEvent) + Sync),
+) -> usize {
+    progress(ProgressEvent::PhaseStarted { phase: Phase::LoadingCache, total_files: cache_files.len(), total_bytes: 0 });
+    let mut loaded = 0;
+    for (i, path) in cache_files.iter().enumerate() {
+        loaded += cache.attach(open_store(path), path.parent().unwrap_or(root), &wanted).unwrap_or(0);
+        progress(ProgressEvent::Progress { phase: Phase::LoadingCache, files: i + 1, bytes: 0 });
+    }
+    loaded
+}
+
+/// Keep one path per hardlinked inode: the first once `order` sorted its aliases
+pub fn one_path_per_inode(files: Vec<FileInfo>, mut order: impl FnMut(&mut [FileInfo])) -> Vec<FileInfo> {
+    let mut unique = Vec::with_capacity(files.len());
+    let mut aliases: HashMap<(Option<u64>, u64, u64), Vec<FileInfo>> = HashMap::new();
+    for f in files {
+        match f.inode {
+            Some(ino) if ino != 0 => aliases.entry((f.dev, ino, f.size)).or_default().push(f),
+            _ => unique.push(f),
+        }
+    }
+    for mut paths in aliases.into_values() {
+        order(&mut paths);
+        unique.extend(paths.into_iter().next());
+    }
+    unique
+}
+
+/// Drop the files whose size no other file shares, they can't have a duplicate
+pub fn retain_shared_sizes(files: &mut Vec<FileInfo>) {
+    let mut size_counts: HashMap<u64, usize> = HashMap::new();
+    for f in files.iter() {
+        *size_counts.entry(f.size).or_default() += 1;
+    }
+    files.retain(|f| size_counts[&f.size] > 1);
+}
+
+/// Files and hash cache of one scanned root
+struct Scan {
+    files: Vec<FileInfo>,
+    cache: HashCache,
+    /// Files discovered, before hardlinks and sizes were filtered
+    discovered: usize,
+    /// Entries discovery couldn't read
+    unreadable: usize,
+}
+
+/// Discover the files below `root` within the size range, one path per
+/// hardlinked inode, and load every cache found along the way. New hashes go
+/// to `cache_path`.
+fn scan(
+    root: &Path,
+    config: &FindConfig,
+    cache_path: Option<&Path>,
+    progress: &(dyn Fn(ProgressEvent) + Sync),
+) -> Result<Scan> {
+    let algo = config.algorithm;
+    let Collected { files, cache_files, unreadable } = collect(discover(root, &config.discover), algo, progress);
+
+    let store: Box<dyn CacheStore> = match cache_path {
+        Some(path) => open_store(path),
+        None => Box::new(NoStore),
+    };
+    let mut cache = HashCache::new(store, root.to_path_buf(), algo, config.hashing.encoding);
+    attach_caches(&mut cache, &cache_files, root, |_| true, progress);
+
+    let discovered = files.len();
+    // The first alias by relative path
+    let mut files = one_path_per_inode(files, |aliases| aliases.sort_by(|a, b| a.rel_path.cmp(&b.rel_path)));
+    files.retain(|f| f.size >= config.min_size && f.size <= config.max_size);
+    Ok(Scan { files, cache, discovered, unreadable })
+}
+
+/// Pair every file with its grouping key, hashing (and caching) content where
+/// the algorithm needs it
+fn key_files(
+    files: Vec<FileInfo>,
+    cache: &mut HashCache,
+    config: &FindConfig,
+    progress: &(dyn Fn(ProgressEvent) + Sync),
+) -> Result<Vec<(FileInfo, String)>> {
+    let algo = config.algorithm;
+    if !algo.hashes_content() {
+        return Ok(files
+            .into_iter()
+            .map(|f| {
+                let key = metadata_key(&f, algo).unwrap_or_default();
+                (f, key)
+            })
+            .collect());
+    }
+    let (mut keyed, files_to_hash) = split_cached(files, cache, algo, config.hashing.full_hash_max);
+    keyed.extend(hash_files(
+        files_to_hash,
+        algo,
+        &config.hashing,
+        cache,
+        None,
+        &AtomicBool::new(false),
+        progress,
+    )?);
+    Ok(keyed)
+}
+
+/// Scan `config.root` and return every group of duplicates, members and groups
+/// sorted by relative path. Hardlinked aliases count once. `progress` is called
+/// from several threads while hashing.
+pub fn find_duplicates(config: &FindConfig, progress: impl Fn(ProgressEvent) + Sync) -> Result<Vec<Vec<FileInfo>>> {
+    let Scan { mut files, mut cache, .. } = scan(&config.root, config, config.cache_path.as_deref(), &progress)?;
+
+    if config.algorithm.hashes_content() {
+        retain_shared_sizes(&mut files);
+    }
+    let keyed = key_files(files, &mut cache, config, &progress)?;
+
+    progress(ProgressEvent::PhaseStarted { phase: Phase::Grouping, total_files: 0, total_bytes: 0 });
+    let mut groups: HashMap<String, Vec<FileInfo>> = HashMap::new();
+    for (f, key) in keyed {
+        groups.entry(key).or_default().push(f);
+    }
+    let mut groups: Vec<Vec<FileInfo>> = groups.into_values().filter(|g| g.len() > 1).collect();
+    for group in &mut groups {
+        group.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+    }
+    groups.sort_by(|a, b| a[0].rel_path.cmp(&b[0].rel_path));
+    Ok(groups)
+}
+
+/// Scan `config.root` and `other` and return every file below `other` that has
+/// a duplicate below `config.root`, together with those duplicates, sorted by
+/// relative path. Files that are the very same file on disk as their match are
+/// reported as aliases instead. Nothing is written below `config.root`:
+/// `config.cache_path` only receives the new hashes of files below `other`.
+pub fn find_copies(config: &FindConfig, other: &Path, progress: impl Fn(ProgressEvent) + Sync) -> Result<Copies> {
+    let mut keep = scan(&config.root, config, None, &progress)?;
+    let mut check = scan(other, config, config.cache_path.as_deref(), &progress)?;
+
+    // A copy needs a counterpart of the same size on the other side
+    if config.algorithm.hashes_content() {
+        let keep_sizes: HashSet<u64> = keep.files.iter().map(|f| f.size).collect();
+        let check_sizes: HashSet<u64> = check.files.iter().map(|f| f.size).collect();
+        keep.files.retain(|f| check_sizes.contains(&f.size));
+        check.files.retain(|f| keep_sizes.contains(&f.size));
+    }
+    let to_key = keep.files.len() + check.files.len();
+    let kept = key_files(keep.files, &mut keep.cache, config, &progress)?;
+    let checked = key_files(check.files, &mut check.cache, config, &progress)?;
+    // Files that failed to hash come back without a key
+    let unhashed = to_key - kept.len() - checked.len();
+
+    progress(ProgressEvent::PhaseStarted { phase: Phase::Grouping, total_files: 0, total_bytes: 0 });
+    let mut by_key: HashMap<String, Vec<FileInfo>> = HashMap::new();
+    for (f, key) in kept {
+        by_key.entry(key).or_default().push(f);
+    }
+    let mut found = Copies {
+        files_scanned: keep.discovered + check.discovered,
+        errors: keep.unreadable + check.unreadable + unhashed,
+        ..Copies::default()
+    };
+    for (f, key) in checked {
+        let Some(originals) = by_key.get(&key) else {
+            continue;
+        };
+        if let Some(original) = originals.iter().find(|o| same_file(o, &f)) {
+            found.aliases.push((f, original.clone()));
+            continue;
+        }
+        let mut originals = originals.clone();
+        originals.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+        found.copies.push((f, originals));
+    }
+    found.copies.sort_by(|a, b| a.0.rel_path.cmp(&b.0.rel_path));
+    found.aliases.sort_by(|a, b| a.0.rel_path.cmp(&b.0.rel_path));
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::Arc;
+    use crate::models::HashEncoding;
+    use std::time::{Duration, Instant};
+
+    fn rel_paths(group: &[FileInfo]) -> Vec<&str> {
+        group.iter().map(|f| f.rel_path.as_str()).collect()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_to_duplicate_is_grouped_with_its_target() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "same").unwrap();
+        fs::write(dir.path().join("b.txt"), "same").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("a.txt"), dir.path().join("link.txt")).unwrap();
+
+        let mut config = FindConfig::new(dir.path().to_path_buf());
+        let groups = find_duplicates(&config, |_| {}).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(rel_paths(&groups[0]), ["a.txt", "b.txt"]);
+
+        config.discover.hash_symlink_targets = true;
+        let groups = find_duplicates(&config, |_| {}).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(rel_paths(&groups[0]), ["a.txt", "b.txt", "link.txt"]);
+        let link = &groups[0][2];
+        assert_eq!(link.link_target.as_deref(), Some(dir.path().join("a.txt").as_path()));
+    }
+
+    fn archive_config(root: &Path) -> FindConfig {
+        let mut config = FindConfig::new(root.to_path_buf());
+        config.discover.scan_archives = true;
+        config
+    }
+
+    #[test]
+    fn zip_entry_matches_loose_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("report.txt"), "quarterly numbers").unwrap();
+        let mut zip = zip::ZipWriter::new(fs::File::create(dir.path().join("backup.zip")).unwrap());
+        zip.start_file("docs/report.txt", zip::write::SimpleFileOptions::default()).unwrap();
+        zip.write_all(b"quarterly numbers").unwrap();
+        zip.start_file("docs/other.txt", zip::write::SimpleFileOptions::default()).unwrap();
+        zip.write_all(b"something else").unwrap();
+        zip.finish().unwrap();
+
+        let groups = find_duplicates(&archive_config(dir.path()), |_| {}).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(rel_paths(&groups[0]), ["backup.zip!docs/report.txt", "report.txt"]);
+        assert_eq!(groups[0][0].archive_entry.as_deref(), Some("docs/report.txt"));
+    }
+
+    #[test]
+    fn tar_gz_entries_match_loose_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let gz = flate2::write::GzEncoder::new(
+            fs::File::create(dir.path().join("backup.tar.gz")).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut tar = tar::Builder::new(gz);
+        for i in 0..50 {
+            let content = format!("entry {}", i % 25);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, format!("{:02}.txt", i), content.as_bytes()).unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+        fs::write(dir.path().join("loose.txt"), "entry 7").unwrap();
+
+        let groups = find_duplicates(&archive_config(dir.path()), |_| {}).unwrap();
+        assert_eq!(groups.len(), 25);
+        let with_loose = groups.iter().find(|g| g.len() == 3).unwrap();
+        assert_eq!(
+            rel_paths(with_loose),
+            ["backup.tar.gz!07.txt", "backup.tar.gz!32.txt", "loose.txt"]
+        );
+
+        // One pass gives the same hashes as opening every entry on its own
+        let entries: Vec<FileInfo> = groups.into_iter().flatten().filter(|f| f.archive_entry.is_some()).collect();
+        let expected: Vec<String> = entries.iter().map(|f| hash_file(f, Algorithm::Md5, &HashOptions::default()).unwrap()).collect();
+        let hashed: Vec<String> = hash_entries(entries, Algorithm::Md5, &HashOptions::default())
+            .into_iter()
+            .map(|(_, h)| h.unwrap())
+            .collect();
+        assert_eq!(hashed, expected);
+    }
+
+    #[test]
+    fn hashes_finished_before_an_interrupt_are_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..200 {
+            fs::write(dir.path().join(format!("{:03}.txt", i)), format!("file {}", i)).unwrap();
+        }
+        let files: Vec<FileInfo> = discover(dir.path(), &DiscoverOptions::default()).map(Result::unwrap).collect();
+        let cache_path = dir.path().join("hashes.csv");
+        let store = open_store(&cache_path);
+        let mut cache = HashCache::new(store, dir.path().to_path_buf(), Algorithm::Md5, HashEncoding::Hex);
+
+        // Interrupt as soon as the first file is done
+        let interrupted = AtomicBool::new(false);
+        let options = HashOptions::default();
+        let hashed = hash_files(files, Algorithm::Md5, &options, &mut cache, None, &interrupted, &|event| {
+            if let ProgressEvent::Progress { .. } = event {
+                interrupted.store(true, Ordering::SeqCst);
+            }
+        })
+        .unwrap();
+        assert!(!hashed.is_empty() && hashed.len() < 200, "{} hashed", hashed.len());
+
+        let mut reloaded =
+            HashCache::new(Box::new(NoStore), dir.path().to_path_buf(), Algorithm::Md5, HashEncoding::Hex);
+        assert_eq!(reloaded.load(&*open_store(&cache_path), dir.path()).unwrap(), hashed.len());
+        for (f, hash) in &hashed {
+            assert_eq!(reloaded.get(&f.rel_path, f.size, f.mtime, Algorithm::Md5).as_ref(), Some(hash));
+        }
+    }
+
+    #[test]
+    fn progress_reports_every_phase_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "same").unwrap();
+        fs::write(dir.path().join("b.txt"), "same").unwrap();
+        fs::write(dir.path().join("c.txt"), "other size").unwrap();
+
+        let events = std::sync::Mutex::new(Vec::new());
+        let groups = find_duplicates(&FindConfig::new(dir.path().to_path_buf()), |event| {
+            events.lock().unwrap().push(event);
+        })
+        .unwrap();
+        assert_eq!(groups.len(), 1);
+
+        let events = events.into_inner().unwrap();
+        let started: Vec<Phase> = events
+            .iter()
+            .filter_map(|e| match e {
+                ProgressEvent::PhaseStarted { phase, .. } => Some(*phase),
+                ProgressEvent::Progress { .. } => None,
+            })
+            .collect();
+        assert_eq!(started, [Phase::Discovering, Phase::LoadingCache, Phase::Hashing, Phase::Grouping]);
+        // Only the two files sharing a size are hashed
+        let hashing = ProgressEvent::PhaseStarted { phase: Phase::Hashing, total_files: 2, total_bytes: 8 };
+        assert!(events.contains(&hashing));
+        assert!(events.contains(&ProgressEvent::Progress { phase: Phase::Hashing, files: 2, bytes: 8 }));
+
+        // Every update belongs to the phase that started last
+        let mut current = None;
+        for event in &events {
+            match event {
+                ProgressEvent::PhaseStarted { phase, .. } => current = Some(*phase),
+                ProgressEvent::Progress { phase, .. } => assert_eq!(Some(*phase), current),
+            }
+        }
+    }
+
+    /// Store counting the entries appended to it
+    struct CountingStore(Arc<AtomicUsize>);
+
+    impl CacheStore for CountingStore {
+        fn load(&self) -> Result<HashMap<CacheKey, String>> {
+            Ok(HashMap::new())
+        }
+
+        fn append(&mut self, entries: &[HashEntry]) -> Result<()> {
+            self.0.fetch_add(entries.len(), Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn streamed_hashes_match_hashing_each_file_and_reach_the_cache_early() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..20 {
+            fs::write(dir.path().join(format!("{:02}.txt", i)), format!("file {}", i % 5)).unwrap();
+        }
+        let files: Vec<FileInfo> = discover(dir.path(), &DiscoverOptions::default()).map(Result::unwrap).collect();
+        let options = HashOptions::default();
+        let mut expected: Vec<(String, String)> = files
+            .iter()
+            .map(|f| (f.rel_path.clone(), hash_file(f, Algorithm::Md5, &options).unwrap()))
+            .collect();
+        expected.sort();
+
+        let stored = Arc::new(AtomicUsize::new(0));
+        let store = Box::new(CountingStore(stored.clone()));
+        let mut cache = HashCache::new(store, dir.path().to_path_buf(), Algorithm::Md5, HashEncoding::Hex);
+        let mut live = Vec::new();
+        let cached_early = AtomicBool::new(false);
+        // One worker, so every file before the current one has been sent to the writer
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let interrupted = AtomicBool::new(false);
+        let hashed = pool.install(|| {
+            hash_files(files, Algorithm::Md5, &options, &mut cache, Some(&mut live), &interrupted, &|event| {
+                if let ProgressEvent::Progress { files: 10, .. } = event {
+                    let deadline = Instant::now() + Duration::from_secs(5);
+                    while stored.load(Ordering::SeqCst) == 0 && Instant::now() < deadline {
+                        thread::yield_now();
+                    }
+                    cached_early.store(stored.load(Ordering::SeqCst) > 0, Ordering::SeqCst);
+                }
+            })
+        })
+        .unwrap();
+        assert!(cached_early.load(Ordering::SeqCst), "nothing was cached while hashing");
+        assert_eq!(stored.load(Ordering::SeqCst), 20);
+
+        let mut streamed: Vec<(String, String)> = hashed.into_iter().map(|(f, hash)| (f.rel_path, hash)).collect();
+        streamed.sort();
+        assert_eq!(streamed, expected);
+        let mut rows: Vec<(String, String)> = String::from_utf8(live)
+            .unwrap()
+            .lines()
+            .map(|row| {
+                let fields: Vec<&str> = row.split(';').collect();
+                (fields[2].to_string(), fields[0].to_string())
+            })
+            .collect();
+        rows.sort();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn grouping_is_the_same_in_every_hash_encoding() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = [("a.txt", "one"), ("b.txt", "one"), ("c.txt", "two"), ("d.txt", "two"), ("e.txt", "three")];
+        for (name, contents) in files {
+            fs::write(dir.path().join(name), contents).unwrap();
+        }
+        let mut config = FindConfig::new(dir.path().to_path_buf());
+        // Shared by the runs, so each one also loads hashes cached in another encoding
+        config.cache_path = Some(dir.path().join("duplicates.hashes.csv"));
+        for encoding in [HashEncoding::Hex, HashEncoding::Base64, HashEncoding::Base32, HashEncoding::Hex] {
+            config.hashing.encoding = encoding;
+            let groups = find_duplicates(&config, |_| {}).unwrap();
+            let mut groups: Vec<Vec<&str>> = groups.iter().map(|g| rel_paths(g)).collect();
+            groups.sort();
+            assert_eq!(groups, [["a.txt", "b.txt"], ["c.txt", "d.txt"]], "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn collecting_sets_aside_the_caches_of_the_algorithm_and_drops_other_internal_files() {
+        use crate::cache::cache_file_name;
+        use crate::models::CacheFormat;
+        let dir = tempfile::tempdir().unwrap();
+        let shared = cache_file_name(None, CacheFormat::Csv);
+        let md5 = cache_file_name(Some(Algorithm::Md5), CacheFormat::Sqlite);
+        let sha256 = cache_file_name(Some(Algorithm::Sha256), CacheFormat::Csv);
+        for name in ["a.txt", &shared, &md5, &format!("{}-wal", md5), &sha256] {
+            fs::write(dir.path().join(name), "x").unwrap();
+        }
+        let events = std::sync::Mutex::new(Vec::new());
+        let collected = collect(discover(dir.path(), &DiscoverOptions::default()), Algorithm::Md5, &|event| {
+            events.lock().unwrap().push(event)
+        });
+        assert_eq!(rel_paths(&collected.files), ["a.txt"]);
+        let mut cache_names: Vec<_> =
+            collected.cache_files.iter().map(|p| p.file_name().unwrap().to_string_lossy().into_owned()).collect();
+        cache_names.sort();
+        let mut expected = [shared, md5];
+        expected.sort();
+        assert_eq!(cache_names, expected);
+        assert_eq!(collected.unreadable, 0);
+        let last = ProgressEvent::Progress { phase: Phase::Discovering, files: 1, bytes: 0 };
+        assert_eq!(events.into_inner().unwrap().last(), Some(&last));
+    }
+}
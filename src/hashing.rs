@@ -1,39 +1,223 @@
-use crate::models::Algorithm;
-use anyhow::Result;
+use crate::archive;
+use crate::error::{DedupError, IoResultExt, Result};
+use crate::models::{Algorithm, FileInfo, HashEncoding};
 use crc32fast::Hasher;
+use data_encoding::{BASE32_NOPAD, BASE64_NOPAD};
 use md5::Md5;
 use sha2::{Digest, Sha256, Sha512};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-pub fn validate_hash(hash: &str, algo: Algorithm) -> bool {
-    if hash.is_empty() {
-        return false;
+/// Number of evenly spaced chunks read by [`sampled_hash`], including the first and last
+const SAMPLE_COUNT: u64 = 16;
+/// Length of each sampled chunk
+const SAMPLE_LEN: u64 = 64 * 1024;
+
+/// Don't bother sleeping for less than this, readers may run ahead by this much
+const THROTTLE_SLACK: Duration = Duration::from_millis(10);
+
+/// Read budget shared by all hashing threads. `next_free` is the point in time
+/// (ns since `start`) at which every byte read so far has been paid for.
+#[derive(Debug)]
+pub struct IoLimiter {
+    bytes_per_sec: u64,
+    start: Instant,
+    next_free: AtomicU64,
+}
+
+impl IoLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            start: Instant::now(),
+            next_free: AtomicU64::new(0),
+        }
+    }
+
+    /// Account for `bytes` just read and sleep until the budget has caught up
+    fn consume(&self, bytes: usize) {
+        let cost = (bytes as u128 * 1_000_000_000 / self.bytes_per_sec as u128) as u64;
+        let now = self.start.elapsed().as_nanos() as u64;
+        // Idle time doesn't build up credit, so there are no bursts after a pause
+        let reserved = self
+            .next_free
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |free| Some(free.max(now) + cost))
+            .unwrap_or(now);
+        let ready_at = Duration::from_nanos(reserved.max(now) + cost);
+        let ahead = ready_at.saturating_sub(Duration::from_nanos(now));
+        if ahead > THROTTLE_SLACK {
+            std::thread::sleep(ahead);
+        }
+    }
+}
+
+/// Reader that charges everything it reads to a shared [`IoLimiter`]
+struct Throttled<'a, R> {
+    inner: R,
+    limiter: &'a IoLimiter,
+}
+
+impl<R: Read> Read for Throttled<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.limiter.consume(count);
+        Ok(count)
+    }
+}
+
+/// How the files of a run are read for hashing
+#[derive(Debug, Clone, Default)]
+pub struct HashOptions {
+    /// Files larger than this get a sampled instead of a full hash
+    pub full_hash_max: Option<u64>,
+    /// Cap on the combined read throughput of all hashing threads, unlimited if `None`
+    pub io_limit: Option<Arc<IoLimiter>>,
+    /// Text form of the computed hashes
+    pub encoding: HashEncoding,
+}
+
+impl HashOptions {
+    /// `reader`, charged to the read budget if there is one
+    pub fn throttle<'a>(&'a self, reader: impl Read + 'a) -> Box<dyn Read + 'a> {
+        match &self.io_limit {
+            Some(limiter) => Box::new(Throttled { inner: reader, limiter }),
+            None => Box::new(reader),
+        }
     }
+}
+
+pub fn encode_hash(digest: &[u8], encoding: HashEncoding) -> String {
+    match encoding {
+        HashEncoding::Hex => hex::encode(digest),
+        HashEncoding::Base64 => BASE64_NOPAD.encode(digest),
+        HashEncoding::Base32 => BASE32_NOPAD.encode(digest),
+    }
+}
+
+pub fn decode_hash(hash: &str, encoding: HashEncoding) -> Option<Vec<u8>> {
+    match encoding {
+        HashEncoding::Hex => hex::decode(hash).ok(),
+        HashEncoding::Base64 => BASE64_NOPAD.decode(hash.as_bytes()).ok(),
+        HashEncoding::Base32 => BASE32_NOPAD.decode(hash.as_bytes()).ok(),
+    }
+}
 
-    // Check if hash contains only valid hex characters
-    if !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+pub fn validate_hash(hash: &str, algo: Algorithm, encoding: HashEncoding) -> bool {
+    if hash.is_empty() {
         return false;
     }
 
-    // Validate length based on algorithm
+    // Validate the digest length based on algorithm
     let expected_len = match algo {
-        Algorithm::Md5 => 32,
-        Algorithm::Sha256 => 64,
-        Algorithm::Sha512 => 128,
-        Algorithm::Crc32 => 8,
+        Algorithm::Md5 => 16,
+        Algorithm::Sha256 => 32,
+        Algorithm::Sha512 => 64,
+        Algorithm::Crc32 => 4,
         _ => return true, // Name and Size don't use hashes
     };
 
-    hash.len() == expected_len
+    decode_hash(hash, encoding).is_some_and(|digest| digest.len() == expected_len)
+}
+
+pub fn calculate_hash(path: &Path, algo: Algorithm, options: &HashOptions) -> Result<String> {
+    hash_reader(&mut File::open(path).at(path)?, algo, options)
+}
+
+/// Hash a discovered file, reading archive entries out of their archive
+pub fn hash_file(file: &FileInfo, algo: Algorithm, options: &HashOptions) -> Result<String> {
+    match &file.archive_entry {
+        Some(entry) => archive::with_entry(&file.path, entry, |reader| hash_reader(reader, algo, options)),
+        None => calculate_hash(&file.path, algo, options),
+    }
+}
+
+/// Hash entries of the one archive they all share, in a single pass over the
+/// archive instead of one per entry
+pub fn hash_entries(
+    entries: Vec<FileInfo>,
+    algo: Algorithm,
+    options: &HashOptions,
+) -> Vec<(FileInfo, Result<String>)> {
+    let Some(archive) = entries.first().map(|f| f.path.clone()) else {
+        return Vec::new();
+    };
+    let wanted: HashSet<&str> = entries.iter().filter_map(|f| f.archive_entry.as_deref()).collect();
+    // Errors as messages, an entry name can appear more than once
+    let mut hashes: HashMap<String, std::result::Result<String, String>> = HashMap::new();
+    let read = archive::for_each_entry(&archive, |name, reader| {
+        if wanted.contains(name) && !hashes.contains_key(name) {
+            hashes.insert(name.to_string(), hash_reader(reader, algo, options).map_err(|e| e.to_string()));
+        }
+        Ok(())
+    });
+    let failure = read.err().map(|e| e.to_string());
+    entries
+        .into_iter()
+        .map(|f| {
+            let name = f.archive_entry.as_deref().unwrap_or_default();
+            let hash = hashes
+                .get(name)
+                .cloned()
+                .unwrap_or_else(|| Err(failure.clone().unwrap_or_else(|| format!("Entry {} not found", name))))
+                .map_err(|reason| DedupError::Archive {
+                    path: archive.clone(),
+                    source: reason.into(),
+                });
+            (f, hash)
+        })
+        .collect()
+}
+
+/// Hash the size and evenly spaced chunks of a file, for files too large to
+/// read in full. Files equal in size and sampled content are only *probably* equal.
+/// Archive entries can't be seeked and small files are cheap, both are hashed in full.
+pub fn sampled_hash(file: &FileInfo, algo: Algorithm, options: &HashOptions) -> Result<String> {
+    if file.archive_entry.is_some() || file.size <= SAMPLE_COUNT * SAMPLE_LEN {
+        return hash_file(file, algo, options);
+    }
+    let mut reader = File::open(&file.path).at(&file.path)?;
+    // Files that only differ in length can have the same samples, e.g. all zeros
+    let mut samples = Vec::with_capacity((8 + SAMPLE_COUNT * SAMPLE_LEN) as usize);
+    samples.extend_from_slice(&file.size.to_le_bytes());
+    for i in 0..SAMPLE_COUNT {
+        let offset = (file.size - SAMPLE_LEN) * i / (SAMPLE_COUNT - 1);
+        reader.seek(SeekFrom::Start(offset)).at(&file.path)?;
+        options.throttle((&mut reader).take(SAMPLE_LEN)).read_to_end(&mut samples).at(&file.path)?;
+    }
+    // Only the reads from disk count against the budget
+    digest(&mut samples.as_slice(), algo, options.encoding)
+}
+
+/// Read at most `len` bytes from the start of a discovered file, archive entries included
+pub fn read_prefix(file: &FileInfo, len: u64) -> Result<Vec<u8>> {
+    let mut prefix = Vec::new();
+    match &file.archive_entry {
+        Some(entry) => archive::with_entry(&file.path, entry, |reader| {
+            reader.take(len).read_to_end(&mut prefix)?;
+            Ok(())
+        })?,
+        None => {
+            let reader = File::open(&file.path).at(&file.path)?;
+            reader.take(len).read_to_end(&mut prefix).at(&file.path)?;
+        }
+    }
+    Ok(prefix)
 }
 
-pub fn calculate_hash(path: &Path, algo: Algorithm) -> Result<String> {
-    let mut file = File::open(path)?;
+pub fn hash_reader(file: &mut dyn Read, algo: Algorithm, options: &HashOptions) -> Result<String> {
+    digest(&mut options.throttle(file), algo, options.encoding)
+}
+
+/// Hash everything `file` yields, without throttling
+pub fn digest(file: &mut dyn Read, algo: Algorithm, encoding: HashEncoding) -> Result<String> {
     let mut buffer = [0; 8192];
 
-    match algo {
+    let digest = match algo {
         Algorithm::Md5 => {
             let mut context = Md5::new();
             loop {
@@ -43,7 +227,7 @@ pub fn calculate_hash(path: &Path, algo: Algorithm) -> Result<String> {
                 }
                 context.update(&buffer[..count]);
             }
-            Ok(hex::encode(context.finalize()))
+            context.finalize().to_vec()
         }
         Algorithm::Sha256 => {
             let mut context = Sha256::new();
@@ -54,7 +238,7 @@ pub fn calculate_hash(path: &Path, algo: Algorithm) -> Result<String> {
                 }
                 context.update(&buffer[..count]);
             }
-            Ok(hex::encode(context.finalize()))
+            context.finalize().to_vec()
         }
         Algorithm::Sha512 => {
             let mut context = Sha512::new();
@@ -65,7 +249,7 @@ pub fn calculate_hash(path: &Path, algo: Algorithm) -> Result<String> {
                 }
                 context.update(&buffer[..count]);
             }
-            Ok(hex::encode(context.finalize()))
+            context.finalize().to_vec()
         }
         Algorithm::Crc32 => {
             let mut hasher = Hasher::new();
@@ -76,8 +260,53 @@ pub fn calculate_hash(path: &Path, algo: Algorithm) -> Result<String> {
                 }
                 hasher.update(&buffer[..count]);
             }
-            Ok(format!("{:08x}", hasher.finalize()))
+            hasher.finalize().to_be_bytes().to_vec()
+        }
+        _ => return Ok(String::new()),
+    };
+    Ok(encode_hash(&digest, encoding))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayon::prelude::*;
+
+    #[test]
+    fn io_limit_is_shared_by_all_hashing_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = (0..4)
+            .map(|i| {
+                let path = dir.path().join(format!("{}.bin", i));
+                std::fs::write(&path, vec![i as u8; 32 * 1024]).unwrap();
+                path
+            })
+            .collect();
+        let options = HashOptions {
+            io_limit: Some(Arc::new(IoLimiter::new(256 * 1024))),
+            ..Default::default()
+        };
+
+        // 128 KiB at 256 KiB/s, however many threads read it
+        let started = Instant::now();
+        paths.par_iter().for_each(|path| {
+            calculate_hash(path, Algorithm::Md5, &options).unwrap();
+        });
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(500) - THROTTLE_SLACK, "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn every_encoding_round_trips_the_same_digest() {
+        let hex = digest(&mut &b"same bytes"[..], Algorithm::Sha256, HashEncoding::Hex).unwrap();
+        let raw = decode_hash(&hex, HashEncoding::Hex).unwrap();
+        for encoding in [HashEncoding::Hex, HashEncoding::Base64, HashEncoding::Base32] {
+            let hash = digest(&mut &b"same bytes"[..], Algorithm::Sha256, encoding).unwrap();
+            assert_eq!(hash, encode_hash(&raw, encoding));
+            assert_eq!(decode_hash(&hash, encoding).as_deref(), Some(raw.as_slice()));
+            assert!(validate_hash(&hash, Algorithm::Sha256, encoding), "{:?}", encoding);
+            assert!(!validate_hash(&hash, Algorithm::Md5, encoding), "{:?}", encoding);
         }
-        _ => Ok(String::new()),
+        assert_eq!(decode_hash("not base32!", HashEncoding::Base32), None);
     }
 }
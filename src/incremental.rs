@@ -0,0 +1,262 @@
+use crate::discovery::DiscoverOptions;
+use crate::error::{DedupError, IoResultExt, Result};
+use crate::models::{FileInfo, Strictness};
+use crate::platform::file_identity;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Sidecar in the scan root holding what `--incremental` needs from the last run
+pub const STATE_FILE_NAME: &str = "duplicates.state.json";
+
+/// A directory as seen by the last run
+#[derive(Serialize, Deserialize)]
+struct DirState {
+    mtime: u64,
+    /// Names of the subdirectories that were walked
+    subdirs: Vec<String>,
+    /// Everything discovery yielded directly inside it
+    files: Vec<FileInfo>,
+}
+
+/// Directory mtimes and contents recorded by the last `--incremental` run
+#[derive(Serialize, Deserialize)]
+pub struct ScanState {
+    /// Root and discovery options the state was recorded with, see [`fingerprint`]
+    fingerprint: String,
+    /// Keyed by path relative to the root, `""` for the root itself
+    dirs: HashMap<String, DirState>,
+}
+
+impl ScanState {
+    /// Read the state left by the last run, `None` if there is none or it can't be used
+    pub fn load(path: &Path) -> Option<Self> {
+        let file = File::open(path).ok()?;
+        serde_json::from_reader(BufReader::new(file)).ok()
+    }
+
+    /// Overwrite the file in place, so the root's mtime doesn't change with every run
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut out = BufWriter::new(File::create(path).at(path)?);
+        serde_json::to_writer(&mut out, self).map_err(|source| DedupError::State {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        out.flush().at(path)?;
+        Ok(())
+    }
+}
+
+/// Identify the root and the discovery options, a state recorded with
+/// different ones can't be reused
+pub fn fingerprint(root: &Path, opts: &DiscoverOptions) -> String {
+    let mut ignore: Vec<&String> = opts.ignore.iter().collect();
+    ignore.sort();
+    format!(
+        "{}|{}|{}|{}|{:?}|{:?}|{}|{}|{:?}",
+        root.display(),
+        opts.recursive,
+        opts.max_depth,
+        opts.follow_symlinks,
+        ignore,
+        opts.exclude_dirs,
+        opts.hash_symlink_targets,
+        opts.scan_archives,
+        opts.time_source
+    )
+}
+
+/// Which directories have to be walked again, and what is known about the rest
+pub struct Plan {
+    fingerprint: String,
+    /// Directories that are new or changed, to be walked one level deep
+    pub rewalk: Vec<PathBuf>,
+    /// Files of the unchanged directories, as recorded
+    pub reused: Vec<FileInfo>,
+    /// Number of unchanged directories
+    pub reused_dirs: usize,
+    /// Directories reached a second time through a symlink or bind mount loop, not entered again
+    pub cycles: Vec<PathBuf>,
+    /// mtime and subdirectories of every directory seen, by relative path
+    dirs: HashMap<String, (u64, Vec<String>)>,
+}
+
+fn modified_nanos(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// Whether a recorded file still has its recorded size and mtime. Archive
+/// entries carry their archive's mtime, but their own size.
+fn unchanged(f: &FileInfo) -> bool {
+    fs::metadata(&f.path)
+        .is_ok_and(|m| modified_nanos(&m) == f.mtime && (f.archive_entry.is_some() || m.len() == f.size))
+}
+
+/// Subdirectories of `dir` that discovery would enter
+fn list_subdirs(dir: &Path, opts: &DiscoverOptions) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut subdirs: Vec<String> = entries
+        .flatten()
+        .filter(|e| match opts.follow_symlinks {
+            true => e.path().is_dir(),
+            false => e.file_type().is_ok_and(|t| t.is_dir()),
+        })
+        .filter(|e| !opts.ignore.contains(e.file_name().to_string_lossy().as_ref()))
+        .filter(|e| !opts.exclude_dirs.contains(&e.path()))
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .collect();
+    subdirs.sort();
+    subdirs
+}
+
+/// Compare every directory below `root` against `previous`. A directory whose
+/// mtime is unchanged had no entry added, removed or renamed, so its recorded
+/// files are reused and only its subdirectories are checked. Editing a file in
+/// place doesn't touch the directory's mtime: with [`Strictness::Stat`] such
+/// a file sends its directory back to be walked.
+pub fn plan(root: &Path, opts: &DiscoverOptions, previous: Option<&ScanState>, strictness: Strictness) -> Plan {
+    let fingerprint = fingerprint(root, opts);
+    let previous = previous.filter(|state| state.fingerprint == fingerprint);
+    let mut plan = Plan {
+        fingerprint,
+        rewalk: Vec::new(),
+        reused: Vec::new(),
+        reused_dirs: 0,
+        cycles: Vec::new(),
+        dirs: HashMap::new(),
+    };
+
+    let mut visited = HashSet::new();
+    let mut stack = vec![(String::new(), 0)];
+    while let Some((rel, depth)) = stack.pop() {
+        let dir = root.join(&rel);
+        let Ok(metadata) = fs::metadata(&dir) else {
+            continue;
+        };
+        if let Some(identity) = file_identity(&dir) {
+            if !visited.insert(identity) {
+                plan.cycles.push(dir);
+                continue;
+            }
+        }
+        let mtime = modified_nanos(&metadata);
+        let known = previous
+            .and_then(|state| state.dirs.get(&rel))
+            .filter(|known| known.mtime == mtime && mtime != 0)
+            .filter(|known| strictness == Strictness::Trust || known.files.iter().all(unchanged));
+        let subdirs = match known {
+            Some(known) => {
+                plan.reused.extend(known.files.iter().cloned());
+                plan.reused_dirs += 1;
+                known.subdirs.clone()
+            }
+            None => {
+                plan.rewalk.push(dir.clone());
+                list_subdirs(&dir, opts)
+            }
+        };
+        // Discovery lists the directories at `max_depth`, but doesn't enter them
+        if opts.recursive && depth + 1 < opts.max_depth {
+            for name in &subdirs {
+                stack.push((Path::new(&rel).join(name).to_string_lossy().into_owned(), depth + 1));
+            }
+        }
+        plan.dirs.insert(rel, (mtime, subdirs));
+    }
+    plan
+}
+
+impl Plan {
+    /// Record the directories of this plan with `files`, everything discovery
+    /// yielded, for the next run
+    pub fn into_state(self, root: &Path, files: Vec<FileInfo>) -> ScanState {
+        let mut dirs: HashMap<String, DirState> = self
+            .dirs
+            .into_iter()
+            .map(|(rel, (mtime, subdirs))| {
+                let state = DirState {
+                    mtime,
+                    subdirs,
+                    files: Vec::new(),
+                };
+                (rel, state)
+            })
+            .collect();
+        for f in files {
+            let rel_dir = f
+                .path
+                .parent()
+                .and_then(|p| p.strip_prefix(root).ok())
+                .map(|p| p.to_string_lossy().into_owned());
+            if let Some(dir) = rel_dir.and_then(|rel| dirs.get_mut(&rel)) {
+                dir.files.push(f);
+            }
+        }
+        ScanState {
+            fingerprint: self.fingerprint,
+            dirs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::discover_in;
+
+    /// Plan a scan of `root`, walk what the plan says and record the state
+    fn scan(root: &Path, opts: &DiscoverOptions, previous: Option<&ScanState>) -> (Vec<PathBuf>, usize, ScanState) {
+        let mut plan = plan(root, opts, previous, Strictness::Trust);
+        let rewalk = plan.rewalk.clone();
+        let reused_dirs = plan.reused_dirs;
+        let files: Vec<FileInfo> =
+            discover_in(root, opts, std::mem::take(&mut plan.rewalk), std::mem::take(&mut plan.reused), reused_dirs)
+                .collect::<Result<_>>()
+                .unwrap();
+        (rewalk, reused_dirs, plan.into_state(root, files))
+    }
+
+    #[test]
+    fn only_the_modified_subdirectory_is_rewalked() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for rel in ["top.txt", "a/one.txt", "b/two.txt", "b/deep/three.txt"] {
+            let path = root.join(rel);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, rel).unwrap();
+        }
+        let opts = DiscoverOptions {
+            recursive: true,
+            ..DiscoverOptions::default()
+        };
+
+        let (rewalk, reused_dirs, state) = scan(root, &opts, None);
+        assert_eq!(rewalk.len(), 4);
+        assert_eq!(reused_dirs, 0);
+
+        fs::write(root.join("b/new.txt"), "new").unwrap();
+        let mut plan = plan(root, &opts, Some(&state), Strictness::Trust);
+        assert_eq!(plan.rewalk, vec![root.join("b")]);
+        assert_eq!(plan.reused_dirs, 3);
+        let mut reused: Vec<&str> = plan.reused.iter().map(|f| f.rel_path.as_str()).collect();
+        reused.sort();
+        assert_eq!(reused, ["a/one.txt", "b/deep/three.txt", "top.txt"]);
+
+        let reused_dirs = plan.reused_dirs;
+        let mut found: Vec<String> =
+            discover_in(root, &opts, std::mem::take(&mut plan.rewalk), std::mem::take(&mut plan.reused), reused_dirs)
+                .map(|f| f.unwrap().rel_path)
+                .collect();
+        found.sort();
+        assert_eq!(found, ["a/one.txt", "b/deep/three.txt", "b/new.txt", "b/two.txt", "top.txt"]);
+    }
+}
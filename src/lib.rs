@@ -0,0 +1,21 @@
+pub mod archive;
+pub mod cache;
+pub mod canonical;
+pub mod discovery;
+pub mod error;
+pub mod finder;
+pub mod hashing;
+pub mod incremental;
+pub mod models;
+pub mod platform;
+pub mod report;
+pub mod review;
+pub mod rules;
+pub mod script;
+pub mod selection;
+pub mod trash;
+pub mod tui;
+pub mod utils;
+
+pub use discovery::{discover, DiscoverOptions};
+pub use finder::{find_copies, Copies, find_duplicates, FindConfig, Phase, ProgressEvent};
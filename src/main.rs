@@ -1,80 +1,487 @@
-mod cache;
-mod hashing;
-mod models;
-mod platform;
-mod utils;
-
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
+use regex::Regex;
 use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::Write;
-use std::time::UNIX_EPOCH;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use sysinfo::Disks;
-use walkdir::WalkDir;
-
-use crate::cache::HashCache;
-use crate::hashing::calculate_hash;
-use crate::models::{Algorithm, Args, FileInfo, HashEntry, KeepCriteria, Mode};
-use crate::platform::{create_symlink, get_file_index, is_reparse_point};
-use crate::utils::{format_disk_info, get_raw_disk_info};
-
-fn format_size(bytes: u64) -> String {
-    if bytes == u64::MAX {
-        return "∞".to_string();
-    }
-    const TB: u64 = 1024 * 1024 * 1024 * 1024;
-    const GB: u64 = 1024 * 1024 * 1024;
-    const MB: u64 = 1024 * 1024;
-    const KB: u64 = 1024;
-
-    if bytes >= TB {
-        format!("{:.2} TB", bytes as f64 / TB as f64)
-    } else if bytes >= GB {
-        format!("{:.2} GB", bytes as f64 / GB as f64)
-    } else if bytes >= MB {
-        format!("{:.2} MB", bytes as f64 / MB as f64)
-    } else if bytes >= KB {
-        format!("{:.2} KB", bytes as f64 / KB as f64)
-    } else {
-        format!("{} B", bytes)
+
+use duplicates::cache::{cache_file_name, is_cache_sidecar, open_store, parse_cache_file_name, CacheKey, HashCache};
+use duplicates::canonical::{canonical_hash, structured_kind, structured_path_kind, CANONICAL_KEY_PREFIX};
+use duplicates::discovery::discover_in;
+use duplicates::finder::{
+    attach_caches, collect, content_hash, find_copies, hash_each, hash_files, is_sampled, metadata_key,
+    one_path_per_inode, retain_shared_sizes, same_directory, same_file, split_cached, Collected, Copies,
+};
+use duplicates::hashing::{validate_hash, HashOptions, IoLimiter};
+use duplicates::incremental::{self, ScanState, STATE_FILE_NAME};
+use duplicates::models::{Args, Command, EmptyGrouping, FileInfo, KeepCriteria, ListFormat, Mode};
+use duplicates::platform::{check_symlink_support, create_symlink, is_reparse_point};
+use duplicates::report::{write_report, GroupReport, Summary};
+use duplicates::review::Review;
+use duplicates::rules::Rules;
+use duplicates::script::{Script, Shell};
+use duplicates::selection::{choose_keeper, sort_group};
+use duplicates::trash::Trash;
+use duplicates::tui::triage;
+use duplicates::utils::{
+    empty_dirs_after, format_bytes, format_disk_info, generate_run_id, get_raw_disk_info, reclaim_diverges,
+};
+use duplicates::{discover, DiscoverOptions, FindConfig, Phase, ProgressEvent};
+
+/// Log of every run, written to the scanned root
+const LOG_FILE_NAME: &str = "duplicates.log";
+
+/// Whether `path` is one of the files the tool itself keeps in the scanned tree
+fn is_own_file(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name == LOG_FILE_NAME
+        || name == STATE_FILE_NAME
+        || parse_cache_file_name(&name).is_some()
+        || is_cache_sidecar(&name)
+}
+
+/// Resolve `Mode::Auto` to a hardlink when both files share a device, else a symlink
+fn resolve_mode(mode: Mode, keep: &FileInfo, dup: &FileInfo) -> Mode {
+    match mode {
+        Mode::Auto if keep.link_target.is_none() && keep.dev.is_some() && keep.dev == dup.dev => Mode::Hardlink,
+        Mode::Auto => Mode::Symlink,
+        mode => mode,
+    }
+}
+
+/// Duplicate count and reclaimable bytes per lowercase file extension, most wasteful first.
+/// Keepers aren't counted, and neither are link duplicates, which free nothing.
+fn extension_breakdown(groups: &[(String, Vec<FileInfo>)]) -> Vec<(String, usize, u64)> {
+    let mut totals: HashMap<String, (usize, u64)> = HashMap::new();
+    for f in groups.iter().flat_map(|(_, g)| &g[1..]) {
+        let name = f.archive_entry.as_deref().map(Path::new).unwrap_or(&f.path);
+        let extension = name
+            .extension()
+            .map(|e| format!(".{}", e.to_string_lossy().to_lowercase()))
+            .unwrap_or_else(|| "(none)".to_string());
+        let total = totals.entry(extension).or_default();
+        total.0 += 1;
+        if f.link_target.is_none() {
+            total.1 += f.size;
+        }
     }
+    let mut breakdown: Vec<(String, usize, u64)> = totals.into_iter().map(|(e, (n, b))| (e, n, b)).collect();
+    breakdown.sort_by(|a, b| b.2.cmp(&a.2).then(b.1.cmp(&a.1)).then(a.0.cmp(&b.0)));
+    breakdown
 }
 
+/// Split every group further by `key`, so only members sharing it stay together
+fn split_groups(
+    groups: HashMap<String, Vec<FileInfo>>,
+    key: impl Fn(&FileInfo) -> String,
+) -> HashMap<String, Vec<FileInfo>> {
+    let mut split: HashMap<String, Vec<FileInfo>> = HashMap::new();
+    for (group_key, group) in groups {
+        for f in group {
+            split.entry(format!("{}|{}", group_key, key(&f))).or_default().push(f);
+        }
+    }
+    split
+}
+
+/// Split consecutive size clusters of `cluster_lens` files into batches of at
+/// most `limit` files, returning the length of each batch. Clusters are never
+/// split, one larger than `limit` gets a batch of its own.
+fn plan_batches(cluster_lens: &[usize], limit: usize) -> Vec<usize> {
+    let mut batches: Vec<usize> = Vec::new();
+    for &len in cluster_lens {
+        match batches.last_mut() {
+            Some(batch) if *batch + len <= limit => *batch += len,
+            _ => batches.push(len),
+        }
+    }
+    batches
+}
+
+/// Ask before changing anything unless `--yes` was given. Refuses outright when
+/// stdin isn't a terminal, since nobody could answer.
+fn confirm(args: &Args, files: usize, reclaim: u64, groups: usize) -> Result<bool> {
+    if files == 0 || args.yes {
+        return Ok(true);
+    }
+    let verb = args.mode.verb();
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!("Refusing to {} {} files without --yes when stdin is not a terminal", verb, files);
+    }
+    print!(
+        "About to {} {} files reclaiming {} across {} groups. Continue? [y/N] ",
+        verb,
+        files,
+        format_bytes(reclaim),
+        groups
+    );
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Whether `dup` is already a link to the kept file, left by an earlier run.
+/// `keep_real` is the canonical path of the kept file.
+fn already_linked(keep_real: &Path, keep: &FileInfo, dup: &FileInfo) -> bool {
+    if is_reparse_point(&dup.path) {
+        return fs::canonicalize(&dup.path).is_ok_and(|target| target == keep_real);
+    }
+    same_file(keep, dup)
+}
+
+/// Warning for `--verify-reclaim` if the free space went from `free_before` to
+/// `free_after` by far more or less than the `expected` reclaim
+fn reclaim_warning(expected: u64, free_before: u64, free_after: u64) -> Option<String> {
+    let freed = free_after.saturating_sub(free_before);
+    reclaim_diverges(expected, freed).then(|| {
+        format!(
+            "WARNING: Expected to free {}, but free space grew by {}. Other hardlinks, snapshots, filesystem \
+             deduplication or recreated files may be holding on to the space.",
+            format_bytes(expected),
+            format_bytes(freed)
+        )
+    })
+}
+
+/// Print the summary of `--summary-json` as the last line of stdout, timed up to now
+fn print_summary(summary: Option<Summary>, started: Instant) -> Result<()> {
+    if let Some(mut summary) = summary {
+        summary.elapsed_ms = started.elapsed().as_millis();
+        println!("{}", serde_json::to_string(&summary)?);
+    }
+    Ok(())
+}
+
+/// Carry out a resolved `mode` on `dup`, returning the log line and progress message
+fn apply_mode(mode: Mode, keep_path: &Path, dup: &FileInfo, trash: Option<&Trash>) -> Result<(String, &'static str)> {
+    // remove_file on a symlink removes the link, never its target
+    let done = match (mode, dup.link_target.is_some()) {
+        (Mode::Delete, false) => {
+            fs::remove_file(&dup.path)?;
+            (format!("Deleted {}", dup.rel_path), "deleted")
+        }
+        (Mode::Delete, true) => {
+            fs::remove_file(&dup.path)?;
+            (format!("Removed symlink {}", dup.rel_path), "removed symlink")
+        }
+        (Mode::Symlink, false) => {
+            fs::remove_file(&dup.path)?;
+            create_symlink(keep_path, &dup.path)?;
+            (format!("Symlinked {}", dup.rel_path), "symlinked")
+        }
+        (Mode::Symlink, true) => {
+            fs::remove_file(&dup.path)?;
+            create_symlink(keep_path, &dup.path)?;
+            (format!("Repointed symlink {}", dup.rel_path), "repointed")
+        }
+        (Mode::Hardlink, _) => {
+            fs::remove_file(&dup.path)?;
+            fs::hard_link(keep_path, &dup.path)?;
+            (format!("Hardlinked {}", dup.rel_path), "hardlinked")
+        }
+        (Mode::Trash, _) => {
+            let trash = trash.expect("--trash-dir is checked at startup");
+            let dest = trash.move_in(&dup.path, &dup.rel_path)?;
+            (format!("Trashed {} -> {}", dup.rel_path, dest.display()), "trashed")
+        }
+        (Mode::Auto, _) => unreachable!("resolved to a concrete link type above"),
+    };
+    Ok(done)
+}
 
 fn main() -> Result<()> {
+    let started = Instant::now();
     let args = Args::parse();
+    // Checked here rather than by clap, --keep is global and optional for the commands
+    if args.command.is_none() && args.format.is_none() && args.keep.is_none() {
+        Args::command()
+            .error(ErrorKind::MissingRequiredArgument, "--keep <KEEP> is required unless listing with --format")
+            .exit();
+    }
 
     if let Some(t) = args.threads {
         rayon::ThreadPoolBuilder::new().num_threads(t).build_global()?;
     }
+    let hash_options = HashOptions {
+        full_hash_max: args.full_hash_max,
+        io_limit: args.io_limit.map(|limit| Arc::new(IoLimiter::new(limit))),
+        encoding: args.hash_encoding,
+    };
+    // Verification compares full hashes, whatever grouping sampled
+    let full_hash_options = HashOptions {
+        full_hash_max: None,
+        ..hash_options.clone()
+    };
+
+    // First Ctrl-C stops at the next safe point, a second one exits immediately
+    let interrupted = Arc::new(AtomicBool::new(false));
+    {
+        let interrupted = interrupted.clone();
+        ctrlc::set_handler(move || {
+            if interrupted.swap(true, atomic::Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+        })?;
+    }
 
     let abs_path = fs::canonicalize(&args.path).context("Failed to canonicalize path")?;
-    let log_file_path = abs_path.join("duplicates.log");
-    let cache_file_path = abs_path.join("duplicates.hashes.csv");
+    let log_file_path = abs_path.join(LOG_FILE_NAME);
+    let cache_file_path = abs_path.join(cache_file_name(args.cache_per_algorithm.then_some(args.algorithm), args.cache_format));
     let mut log_file = File::create(&log_file_path)?;
 
+    // Tags every log line, report and trash record of this run
+    let run_id = args.run_id.clone().unwrap_or_else(generate_run_id);
+
+    // Keep stdout clean for machine-readable listings
+    let echo = args.format.is_none() && !args.quiet;
     macro_rules! log {
         ($($arg:tt)*) => {
             let msg = format!($($arg)*);
             let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
-            let line = format!("[{}] {}\n", timestamp, msg);
-            print!("{}", line);
+            let line = format!("[{}] [{}] {}\n", timestamp, run_id, msg);
+            if echo {
+                print!("{}", line);
+            }
             log_file.write_all(line.as_bytes())?;
         };
     }
 
     log!(
-        "Settings: Path={:?} | Keep={:?} | Mode={:?} | Algorithm={:?} | Recursive={}",
+        "Settings: Path={:?} | Keep={} | Mode={:?} | Algorithm={:?} | Recursive={}",
         abs_path,
-        args.keep,
+        args.keep.map_or("-".to_string(), |k| format!("{:?}", k)),
         args.mode,
         args.algorithm,
         args.recursive
     );
 
+    if let Some(limit) = args.io_limit {
+        log!("Hashing reads limited to {}/s", format_bytes(limit));
+    }
+
+    let trash = match &args.trash_dir {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            Some(Trash::new(fs::canonicalize(dir)?, run_id.clone()))
+        }
+        None => None,
+    };
+    let needs_trash = args.mode == Mode::Trash || matches!(args.command, Some(Command::Purge { .. } | Command::Restore));
+    if needs_trash && trash.is_none() {
+        anyhow::bail!("--trash-dir is required for the trash mode and the purge/restore commands");
+    }
+
+    match (&args.command, &trash) {
+        (Some(Command::Purge { older_than }), Some(trash)) => {
+            for entry in trash.purge(*older_than)? {
+                log!("  Purged {} (from {})", entry.trashed_path, entry.original_path.display());
+            }
+            log!("Done.");
+            return Ok(());
+        }
+        (Some(Command::Restore), Some(trash)) => {
+            let restored = trash.restore()?;
+            for entry in &restored {
+                log!("  Restored {}", entry.original_path.display());
+            }
+            let remaining = trash.entries()?.len();
+            if remaining > 0 {
+                log!("{} trashed file(s) could not be restored, their original path is occupied", remaining);
+            }
+            log!("Done.");
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    if args.min_size > args.max_size {
+        anyhow::bail!(
+            "--min-size {} is larger than --max-size {}, no file could match",
+            format_bytes(args.min_size),
+            format_bytes(args.max_size)
+        );
+    }
+    if args.min_size == args.max_size {
+        log!(
+            "Warning: --min-size equals --max-size, only files of exactly {} can match",
+            format_bytes(args.min_size)
+        );
+    }
+
+    if args.emit_script.is_some() && !args.dry_run {
+        anyhow::bail!("--emit-script only works together with --dry-run");
+    }
+
+    let path_regex = match &args.path_regex_replace {
+        Some(pair) => Some((
+            Regex::new(&pair[0]).context("Invalid --path-regex-replace pattern")?,
+            pair[1].clone(),
+        )),
+        None => None,
+    };
+
+    if let Some(confirm) = args.confirm_with {
+        if !confirm.hashes_content() {
+            anyhow::bail!("--confirm-with needs a content hash algorithm, not {:?}", confirm);
+        }
+    }
+
+    // Listings never change anything
+    let mutates = args.command.is_none() && args.format.is_none() && !args.dry_run;
+    if mutates && matches!(args.mode, Mode::Symlink | Mode::Auto) {
+        check_symlink_support(&abs_path)?;
+    }
+
+    let rules = match &args.rules {
+        Some(path) => {
+            let rules = Rules::load(path)?;
+            log!("Loaded {} keep rule(s) from {:?}", rules.len(), path);
+            Some(rules)
+        }
+        None => None,
+    };
+    // Keep criteria for a group: the first matching rule, else `default`
+    let keep_for = |group: &[FileInfo], default: KeepCriteria| {
+        rules.as_ref().and_then(|r| r.keep_for(group)).unwrap_or(default)
+    };
+
+    let mut ignore: HashSet<String> = args.ignore.split(',').map(str::to_string).collect();
+    ignore.insert(LOG_FILE_NAME.to_string());
+    ignore.insert(STATE_FILE_NAME.to_string());
+
+    if let Some(Command::Compare { dir_a, dir_b }) = &args.command {
+        let dir_a = fs::canonicalize(dir_a).with_context(|| format!("Failed to canonicalize {:?}", dir_a))?;
+        let dir_b = fs::canonicalize(dir_b).with_context(|| format!("Failed to canonicalize {:?}", dir_b))?;
+        if dir_a.starts_with(&dir_b) || dir_b.starts_with(&dir_a) {
+            anyhow::bail!("compare needs two separate directories, {:?} and {:?} overlap", dir_a, dir_b);
+        }
+        if same_directory(&dir_a, &dir_b) {
+            anyhow::bail!("{:?} and {:?} are the same directory mounted twice", dir_a, dir_b);
+        }
+        if matches!(args.mode, Mode::Symlink | Mode::Auto) && !args.dry_run {
+            check_symlink_support(&dir_b)?;
+        }
+        log!("Looking for files in {:?} that already exist in {:?}...", dir_b, dir_a);
+
+        let mut config = FindConfig::new(dir_a.clone());
+        config.discover = DiscoverOptions {
+            recursive: args.recursive,
+            max_depth: args.max_depth,
+            follow_symlinks: args.follow_symlinks,
+            ignore,
+            exclude_dirs: trash.iter().map(|t| t.dir().to_path_buf()).collect(),
+            hash_symlink_targets: args.hash_symlink_targets,
+            scan_archives: args.scan_archives,
+            time_source: args.time_source,
+        };
+        config.algorithm = args.algorithm;
+        config.min_size = args.min_size;
+        config.max_size = args.max_size;
+        config.hashing = hash_options.clone();
+        config.cache_path = Some(dir_b.join(cache_file_name(
+            args.cache_per_algorithm.then_some(args.algorithm),
+            args.cache_format,
+        )));
+
+        let pb = ProgressBar::new(0);
+        pb.set_style(ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+            .unwrap()
+            .progress_chars("#>-"));
+        let Copies { mut copies, aliases, files_scanned, errors } = find_copies(&config, &dir_b, |event| match event {
+            ProgressEvent::PhaseStarted { phase: Phase::Hashing, total_bytes, .. } => {
+                pb.set_position(0);
+                pb.set_length(total_bytes);
+            }
+            ProgressEvent::Progress { phase: Phase::Hashing, bytes, .. } => pb.set_position(bytes),
+            _ => {}
+        })?;
+        pb.finish_and_clear();
+
+        if !aliases.is_empty() {
+            log!(
+                "WARNING: {} file(s) in {:?} are the same file on disk as their match in {:?} \
+                 (bind mount or hardlink) and are left alone:",
+                aliases.len(),
+                dir_b,
+                dir_a
+            );
+            for (alias, original) in &aliases {
+                log!("  {} is {}", alias.rel_path, original.rel_path);
+            }
+        }
+        log!("{} file(s) in {:?} have a copy in {:?}:", copies.len(), dir_b, dir_a);
+        for (copy, originals) in &mut copies {
+            let keep = keep_for(originals, args.keep.unwrap_or(KeepCriteria::First));
+            sort_group(originals, keep, args.keep_secondary);
+            let originals: Vec<&str> = originals.iter().map(|f| f.rel_path.as_str()).collect();
+            log!("  {} == {}", copy.rel_path, originals.join(", "));
+        }
+
+        // Archive entries can't be changed, and a link can't point into an archive
+        let actionable: Vec<(FileInfo, FileInfo, Mode)> = copies
+            .into_iter()
+            .filter(|(copy, _)| copy.archive_entry.is_none())
+            .filter_map(|(copy, originals)| {
+                let keep = originals.into_iter().next()?;
+                let mode = resolve_mode(args.mode, &keep, &copy);
+                let linkable = keep.archive_entry.is_none() || matches!(mode, Mode::Delete | Mode::Trash);
+                linkable.then_some((copy, keep, mode))
+            })
+            .collect();
+        let reclaim: u64 = actionable.iter().filter(|(f, _, _)| f.link_target.is_none()).map(|(f, _, _)| f.size).sum();
+        // Copies of the same original form one group
+        let groups = actionable.iter().map(|(_, keep, _)| &keep.path).collect::<HashSet<_>>().len();
+        let summary = args.summary_json.then(|| Summary {
+            run_id: run_id.clone(),
+            files_scanned,
+            groups,
+            duplicates: actionable.len(),
+            bytes_reclaimable: reclaim,
+            elapsed_ms: 0,
+            errors,
+        });
+        if args.dry_run {
+            for (copy, _, mode) in &actionable {
+                log!("  [DRY RUN] {} -> {:?}", copy.rel_path, mode);
+            }
+            log!("Done.");
+            print_summary(summary, started)?;
+            return Ok(());
+        }
+
+        if !confirm(&args, actionable.len(), reclaim, groups)? {
+            log!("Aborted by user.");
+            print_summary(summary, started)?;
+            return Ok(());
+        }
+        for (copy, keep, mode) in &actionable {
+            if interrupted.load(atomic::Ordering::SeqCst) {
+                log!("Interrupted, remaining files were left untouched.");
+                break;
+            }
+            let keep_path = if keep.link_target.is_some() {
+                fs::canonicalize(&keep.path)?
+            } else {
+                keep.path.clone()
+            };
+            let (line, _) = apply_mode(*mode, &keep_path, copy, trash.as_ref())?;
+            log!("  {}", line);
+        }
+        log!("Done.");
+        print_summary(summary, started)?;
+        return Ok(());
+    }
+
     let mut disks = Disks::new_with_refreshed_list();
     let initial_disk_stats = get_raw_disk_info(&abs_path, &disks);
     log!(
@@ -84,248 +491,691 @@ fn main() -> Result<()> {
             .unwrap_or_else(|| "Unknown".to_string())
     );
 
-    // 1. Discovery with hash CSV loading
+    // 1. Discovery with hash cache loading
     log!("Scanning directory...");
-    let mut files = Vec::new();
-    let mut hash_csv_files = Vec::new();
-    let ignores: HashSet<&str> = args.ignore.split(',').collect();
-
-    let walker = WalkDir::new(&abs_path)
-        .max_depth(if args.recursive { usize::MAX } else { 1 })
-        .into_iter()
-        .filter_entry(|e| {
-            let name = e.file_name().to_string_lossy();
-            !ignores.contains(name.as_ref()) && name != "duplicates.log"
-        });
+    let discover_options = DiscoverOptions {
+        recursive: args.recursive,
+        max_depth: args.max_depth,
+        follow_symlinks: args.follow_symlinks,
+        ignore,
+        exclude_dirs: trash.iter().map(|t| t.dir().to_path_buf()).collect(),
+        hash_symlink_targets: args.hash_symlink_targets,
+        scan_archives: args.scan_archives,
+        time_source: args.time_source,
+    };
+    let state_path = abs_path.join(STATE_FILE_NAME);
+    let mut scan_plan = args.incremental.map(|strictness| {
+        let previous = ScanState::load(&state_path);
+        incremental::plan(&abs_path, &discover_options, previous.as_ref(), strictness)
+    });
+    // Everything discovery yields, recorded for the next incremental run
+    let mut state_files: Vec<FileInfo> = Vec::new();
+    let mut discovery = match &mut scan_plan {
+        Some(plan) => {
+            log!(
+                "Incremental scan: walking {} new or changed of {} directories",
+                plan.rewalk.len(),
+                plan.rewalk.len() + plan.reused_dirs
+            );
+            for dir in &plan.cycles {
+                log!("Warning: directory loop at {}, not entered again", dir.strip_prefix(&abs_path).unwrap_or(dir).display());
+            }
+            discover_in(
+                &abs_path,
+                &discover_options,
+                std::mem::take(&mut plan.rewalk),
+                std::mem::take(&mut plan.reused),
+                plan.reused_dirs,
+            )
+        }
+        None => discover(&abs_path, &discover_options),
+    };
 
     let pb = ProgressBar::new_spinner();
-    pb.set_style(
-        ProgressStyle::default_spinner().template("{spinner:.green} Discovered {pos} files in {msg} folders...")?,
-    );
-    let mut folder_count = 0;
+    pb.set_style(ProgressStyle::default_spinner().template("{spinner:.green} Discovered {pos} files...")?);
+    let record_state = scan_plan.is_some();
+    let discovered = discovery
+        .by_ref()
+        .take_while(|_| !interrupted.load(atomic::Ordering::SeqCst))
+        .inspect(|file| match file {
+            Ok(file) if record_state => state_files.push(file.clone()),
+            _ => {}
+        });
+    // Only the shared hash cache and our algorithm's are loaded
+    let Collected { mut files, cache_files: hash_cache_files, unreadable } =
+        collect(discovered, args.algorithm, &|event| {
+            if let ProgressEvent::Progress { files, .. } = event {
+                pb.set_position(files as u64);
+            }
+        });
+    pb.finish_and_clear();
+    if interrupted.load(atomic::Ordering::SeqCst) {
+        log!("Interrupted during discovery, nothing was changed.");
+        anyhow::bail!("Interrupted");
+    }
+    // Files that couldn't be read, for the summary
+    let mut read_errors = unreadable;
+    log!("Found {} total files in {} folders.", files.len(), discovery.folder_count());
+    let files_scanned = files.len();
+    for link in discovery.broken_links() {
+        log!("Warning: broken symlink {}", link.strip_prefix(&abs_path).unwrap_or(link).display());
+    }
+    for (link, hops) in discovery.link_chains() {
+        log!(
+            "Warning: symlink chain of {} hops at {}",
+            hops,
+            link.strip_prefix(&abs_path).unwrap_or(link).display()
+        );
+    }
+    for dir in discovery.cycles() {
+        log!("Warning: directory loop at {}, not entered again", dir.strip_prefix(&abs_path).unwrap_or(&dir).display());
+    }
+    let broken_link_count = discovery.broken_links().len();
+    let link_chain_count = discovery.link_chains().len();
+    let ignored = discovery.ignored();
+    if let Some(plan) = scan_plan {
+        plan.into_state(&abs_path, state_files)
+            .save(&state_path)
+            .context("Failed to save the incremental scan state")?;
+    }
 
-    for entry in walker {
-        let entry = match entry {
-            Ok(e) => e,
-            Err(_) => continue,
-        };
-        if entry.file_type().is_dir() {
-            folder_count += 1;
-            pb.set_message(folder_count.to_string());
-            continue;
-        }
-        if !entry.file_type().is_file() {
-            continue;
-        }
-
-        let path = entry.path().to_path_buf();
-        
-        // Check if this is a hash CSV file
-        if path.file_name().and_then(|n| n.to_str()) == Some("duplicates.hashes.csv") {
-            hash_csv_files.push(path);
-            continue;
-        }
-        
-        let metadata = match fs::metadata(&path) {
-            Ok(m) => m,
-            Err(_) => continue,
+    let transform_key_path = |f: &mut FileInfo| {
+        let mut key_path = f.rel_path.as_str();
+        if let Some(stripped) = args.path_strip.iter().find_map(|p| key_path.strip_prefix(p.as_str())) {
+            key_path = stripped;
+        }
+        f.key_path = match &path_regex {
+            Some((pattern, replacement)) => pattern.replace(key_path, replacement.as_str()).into_owned(),
+            None => key_path.to_string(),
         };
+    };
+    if !args.path_strip.is_empty() || path_regex.is_some() {
+        files.iter_mut().for_each(transform_key_path);
+    }
+    let missing_time_count = files.iter().filter(|f| f.keep_time.is_none()).count();
+    if missing_time_count > 0 {
+        log!(
+            "Warning: {:?} is unavailable for {} files, using their mtime instead",
+            args.time_source,
+            missing_time_count
+        );
+    }
+
+    // Caches are loaded once it's known which hashes are needed
+    let mut hash_cache =
+        HashCache::new(open_store(&cache_file_path), abs_path.clone(), args.algorithm, args.hash_encoding);
 
-        // Skip all reparse points (symlinks, junctions, hardlinks, etc.)
-        if is_reparse_point(&path) {
-            continue;
+    if let Some(Command::VerifyIntegrity) = args.command {
+        if !args.algorithm.hashes_content() {
+            anyhow::bail!("verify-integrity needs a content hash algorithm, not {:?}", args.algorithm);
+        }
+        if !hash_cache_files.is_empty() {
+            log!("Loading {} hash cache file(s)...", hash_cache_files.len());
+            let loaded = attach_caches(&mut hash_cache, &hash_cache_files, &abs_path, |_| true, &|_| {});
+            log!("Loaded {} cached hashes from {} file(s)", loaded, hash_cache_files.len());
         }
 
-        let rel_path = path.strip_prefix(&abs_path)?.to_string_lossy().into_owned();
-        let mtime = metadata
-            .modified()
-            .ok()
-            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
-            .map(|d| d.as_nanos() as u64)
-            .unwrap_or(0);
+        // A cache hit implies unchanged size and mtime, so any hash change is silent corruption
+        let baseline: Vec<(FileInfo, String)> = files
+            .into_iter()
+            .filter_map(|f| {
+                let hash = hash_cache.get(&f.rel_path, f.size, f.mtime, args.algorithm)?;
+                Some((f, hash))
+            })
+            .collect();
+        let total_bytes: u64 = baseline.iter().map(|(f, _)| f.size).sum();
+        log!("Verifying {} file(s) against cached hashes...", baseline.len());
 
-        let inode = get_file_index(&path).unwrap_or(None);
+        let pb = ProgressBar::new(total_bytes);
+        pb.set_style(ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+            .unwrap()
+            .progress_chars("#>-"));
 
-        files.push(FileInfo {
-            path,
-            rel_path,
-            size: metadata.len(),
-            mtime,
-            inode,
+        let checked = baseline.len();
+        let (files, expected): (Vec<FileInfo>, HashMap<String, String>) =
+            baseline.into_iter().map(|(f, hash)| (f.clone(), (f.rel_path, hash))).unzip();
+        let mismatches: Mutex<Vec<(FileInfo, String, Option<String>)>> = Mutex::new(Vec::new());
+        hash_each(files, args.algorithm, &full_hash_options, &interrupted, |f, actual| {
+            pb.inc(f.size);
+            let actual = actual.ok();
+            let expected = &expected[&f.rel_path];
+            if actual.as_ref() != Some(expected) {
+                mismatches.lock().unwrap().push((f, expected.clone(), actual));
+            }
         });
-        pb.inc(1);
-    }
-    pb.finish_and_clear();
-    log!("Found {} total files in {} folders.", files.len(), folder_count);
+        pb.finish_and_clear();
+        let mut mismatches = mismatches.into_inner().unwrap();
+        mismatches.sort_by(|a, b| a.0.rel_path.cmp(&b.0.rel_path));
 
-    // Load all discovered hash CSV files
-    let mut hash_cache = HashCache::new(cache_file_path.clone(), abs_path.clone());
-    if !hash_csv_files.is_empty() {
-        log!("Loading {} hash CSV file(s)...", hash_csv_files.len());
-        let mut total_loaded = 0;
-        for csv_path in &hash_csv_files {
-            if let Ok(loaded) = hash_cache.load_csv(csv_path) {
-                total_loaded += loaded;
+        for (f, expected, actual) in &mismatches {
+            match actual {
+                Some(actual) => {
+                    log!("  CORRUPTED {}: expected {}, got {}", f.rel_path, expected, actual);
+                }
+                None => {
+                    log!("  UNREADABLE {}", f.rel_path);
+                }
             }
         }
-        if total_loaded > 0 {
-            log!("Loaded {} cached hashes from {} file(s)", total_loaded, hash_csv_files.len());
+        if interrupted.load(atomic::Ordering::SeqCst) {
+            log!("Interrupted, verification is incomplete.");
         }
+        log!("Verified {} file(s), {} mismatch(es).", checked, mismatches.len());
+        // Nothing is grouped, corrupted files are only counted in the log
+        let summary = args.summary_json.then(|| Summary {
+            run_id: run_id.clone(),
+            files_scanned,
+            groups: 0,
+            duplicates: 0,
+            bytes_reclaimable: 0,
+            elapsed_ms: 0,
+            errors: read_errors + mismatches.iter().filter(|(_, _, actual)| actual.is_none()).count(),
+        });
+        if !mismatches.is_empty() {
+            print_summary(summary, started)?;
+            anyhow::bail!("{} file(s) failed integrity verification", mismatches.len());
+        }
+        log!("Done.");
+        print_summary(summary, started)?;
+        return Ok(());
     }
+    let keep = args.keep.unwrap_or_else(|| {
+        assert!(args.format.is_some(), "--keep is checked after parsing unless listing");
+        KeepCriteria::First
+    });
 
     // 2. Filter hardlinks
     log!("Filtering hardlinks...");
-    let mut seen_inodes = HashSet::new();
-    let mut unique_files = Vec::new();
-    for f in files {
-        if let Some(ino) = f.inode {
-            if ino != 0 && !seen_inodes.insert((ino, f.size)) {
-                continue;
-            }
-        }
-        unique_files.push(f);
-    }
+    // Pick the path representing each inode by the keep rules, so reports are stable
+    let mut unique_files = one_path_per_inode(files, |aliases| sort_group(aliases, keep, args.keep_secondary));
     log!("Unique files to process: {}", unique_files.len());
 
     // 3. Filter by size
     let before_size_filter = unique_files.len();
+    let size_span = unique_files.iter().map(|f| f.size).min().zip(unique_files.iter().map(|f| f.size).max());
     unique_files.retain(|f| f.size >= args.min_size && f.size <= args.max_size);
     let filtered_count = before_size_filter - unique_files.len();
     if filtered_count > 0 {
         log!(
             "Filtered {} files outside size range ({} - {})",
             filtered_count,
-            format_size(args.min_size),
-            format_size(args.max_size)
+            format_bytes(args.min_size),
+            format_bytes(args.max_size)
         );
     }
+    if let (true, Some((smallest, largest))) = (unique_files.is_empty(), size_span) {
+        log!(
+            "WARNING: The size range excludes every file, sizes here run from {} to {}",
+            format_bytes(smallest),
+            format_bytes(largest)
+        );
+    }
+    if !args.exclude_sizes.is_empty() {
+        let before_exclude = unique_files.len();
+        unique_files.retain(|f| !args.exclude_sizes.contains(&f.size));
+        let excluded_count = before_exclude - unique_files.len();
+        if excluded_count > 0 {
+            log!("Filtered {} files with an excluded size", excluded_count);
+        }
+    }
     log!("Files after size filter: {}", unique_files.len());
 
+    // Files modified moments ago may still be being written, e.g. downloads in progress
+    if let Some(window) = args.skip_recently_modified {
+        let cutoff = SystemTime::now()
+            .checked_sub(window)
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos() as u64);
+        let before_recent = unique_files.len();
+        unique_files.retain(|f| f.mtime < cutoff);
+        let recent_count = before_recent - unique_files.len();
+        if recent_count > 0 {
+            log!("Skipped {} files modified within the last {:?}", recent_count, window);
+        }
+    }
+
+    // Sparse files are mostly zeros: slow to hash for little gain, and copies inflate them
+    let sparse_count = unique_files.iter().filter(|f| f.is_sparse()).count();
+    if sparse_count > 0 {
+        for f in unique_files.iter().filter(|f| f.is_sparse()) {
+            log!(
+                "Sparse file {}: {} allocated of {}",
+                f.rel_path,
+                format_bytes(f.allocated.unwrap_or(0)),
+                format_bytes(f.size)
+            );
+        }
+        if args.skip_sparse {
+            unique_files.retain(|f| !f.is_sparse());
+            log!("Skipped {} sparse files", sparse_count);
+        }
+    }
+
+    // Everything still a candidate here is unique unless it ends up in a group
+    let unique_report_candidates: Option<Vec<(String, String)>> = args.unique_report.as_ref().map(|_| {
+        unique_files
+            .iter()
+            .map(|f| (f.rel_path.clone(), f.display_path(args.absolute_paths)))
+            .collect()
+    });
+
+    // Equal documents can differ in size, so they skip the size pre-grouping, and
+    // hashes of their canonical form aren't content hashes, so they stay out of the cache
+    let mut canonical_groups: HashMap<String, Vec<FileInfo>> = HashMap::new();
+    if args.canonical_structured && args.algorithm.hashes_content() {
+        let (structured, rest): (Vec<FileInfo>, Vec<FileInfo>) =
+            unique_files.into_iter().partition(|f| structured_kind(f).is_some());
+        unique_files = rest;
+        log!("Hashing {} JSON/XML files in canonical form...", structured.len());
+        let hashed: Vec<_> = structured
+            .into_par_iter()
+            .map(|f| {
+                let kind = structured_kind(&f).expect("partitioned by kind above");
+                let hash = canonical_hash(&f, kind, args.algorithm, &hash_options);
+                (f, hash)
+            })
+            .collect();
+        for (f, hash) in hashed {
+            match hash {
+                Ok(hash) => canonical_groups.entry(format!("{}{}", CANONICAL_KEY_PREFIX, hash)).or_default().push(f),
+                Err(e) => {
+                    log!("WARNING: {}, comparing it as is", e);
+                    unique_files.push(f);
+                }
+            }
+        }
+        canonical_groups.retain(|_, g| g.len() > 1);
+    }
+
     // 4. Hashing
-    let groups = if args.algorithm == Algorithm::Name {
+    let groups = if !args.algorithm.hashes_content() {
         let mut groups: HashMap<String, Vec<FileInfo>> = HashMap::new();
         for f in unique_files {
-            let name = f
-                .path
-                .file_name()
-                .unwrap_or_default()
-                .to_string_lossy()
-                .into_owned();
-            groups.entry(name).or_default().push(f);
-        }
-        groups
-    } else if args.algorithm == Algorithm::Size {
-        let mut groups: HashMap<u64, Vec<FileInfo>> = HashMap::new();
-        for f in unique_files {
-            groups.entry(f.size).or_default().push(f);
+            let key = metadata_key(&f, args.algorithm).unwrap_or_default();
+            groups.entry(key).or_default().push(f);
         }
         groups
-            .into_values()
-            .filter(|v| v.len() > 1)
-            .map(|v| (v[0].size.to_string(), v))
-            .collect()
     } else {
-        // Use the cache loaded during discovery
-        let mut cache_hits = 0;
-
         log!("Pre-grouping by size...");
-        let mut size_groups: HashMap<u64, Vec<FileInfo>> = HashMap::new();
-        for f in unique_files {
-            size_groups.entry(f.size).or_default().push(f);
+        retain_shared_sizes(&mut unique_files);
+        // Sort by size: smallest first for better progress perception
+        unique_files.sort_by_key(|f| f.size);
+
+        if !hash_cache_files.is_empty() {
+            log!("Loading {} hash cache file(s)...", hash_cache_files.len());
+            // Entries of files that aren't candidates could never be hit
+            let candidates: HashSet<(&str, u64, u64)> =
+                unique_files.iter().map(|f| (f.rel_path.as_str(), f.size, f.mtime)).collect();
+            let wanted = |key: &CacheKey| candidates.contains(&(key.path.as_str(), key.size, key.time));
+            let loaded = attach_caches(&mut hash_cache, &hash_cache_files, &abs_path, wanted, &|_| {});
+            if loaded > 0 {
+                log!("Loaded {} cached hashes from {} file(s)", loaded, hash_cache_files.len());
+            }
+        }
+
+        // Batches are taken off the sorted candidates one at a time and only
+        // their groups are kept, so a batch's files are dropped once it's hashed
+        let candidate_count = unique_files.len();
+        let batch_limit = args.max_candidates.unwrap_or(usize::MAX);
+        let cluster_lens: Vec<usize> = unique_files.chunk_by(|a, b| a.size == b.size).map(<[_]>::len).collect();
+        let batches = plan_batches(&cluster_lens, batch_limit);
+        if batches.len() > 1 {
+            log!(
+                "{} candidates exceed --max-candidates {}, hashing in {} batches",
+                candidate_count,
+                batch_limit,
+                batches.len()
+            );
         }
-        let all_candidates: Vec<FileInfo> = size_groups
-            .into_values()
-            .filter(|v| v.len() > 1)
-            .flatten()
-            .collect();
 
-        // 4. Separate cached from uncached files
-        let mut cached_files: Vec<(FileInfo, String)> = Vec::new();
-        let mut files_to_hash: Vec<FileInfo> = Vec::new();
+        let algo = args.algorithm;
+        let batch_count = batches.len();
+        let mut live_results = match &args.live_results {
+            Some(path) => Some(File::create(path).with_context(|| format!("Failed to create {:?}", path))?),
+            None => None,
+        };
+        let mut groups: HashMap<String, Vec<FileInfo>> = HashMap::new();
 
-        for f in all_candidates {
-            if let Some(hash) = hash_cache.get(&f.rel_path, f.size, f.mtime, args.algorithm) {
-                cached_files.push((f, hash.clone()));
-                cache_hits += 1;
+        let mut remaining = unique_files.into_iter();
+        for (batch_index, batch_len) in batches.into_iter().enumerate() {
+            let batch: Vec<FileInfo> = remaining.by_ref().take(batch_len).collect();
+            // Separate cached from uncached files
+            let (cached_files, files_to_hash) = split_cached(batch, &mut hash_cache, algo, args.full_hash_max);
+
+            let total_bytes: u64 = files_to_hash.iter().map(|f| f.size).sum();
+
+            let batch_label = if batch_count > 1 {
+                format!("Batch {}/{}: ", batch_index + 1, batch_count)
             } else {
-                files_to_hash.push(f);
+                String::new()
+            };
+            log!(
+                "{}Cache: {} hits, {} files ({}) need hashing",
+                batch_label,
+                cached_files.len(),
+                files_to_hash.len(),
+                format_bytes(total_bytes)
+            );
+
+            // Hash files with live cache appending (progress based on bytes)
+            let pb = ProgressBar::new(total_bytes);
+            pb.set_style(ProgressStyle::default_bar()
+                .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+                .unwrap()
+                .progress_chars("#>-"));
+
+            let to_hash_count = files_to_hash.len();
+            let newly_hashed = hash_files(
+                files_to_hash,
+                algo,
+                &hash_options,
+                &mut hash_cache,
+                live_results.as_mut().map(|f| f as &mut (dyn Write + Send)),
+                &interrupted,
+                &|event| {
+                    if let ProgressEvent::Progress { bytes, .. } = event {
+                        pb.set_position(bytes);
+                    }
+                },
+            )
+            .context("Failed to write live results")?;
+            pb.finish_and_clear();
+
+            if interrupted.load(atomic::Ordering::SeqCst) {
+                log!(
+                    "Interrupted during hashing, {} new hashes were saved to the cache. Nothing was changed.",
+                    newly_hashed.len()
+                );
+                anyhow::bail!("Interrupted");
+            }
+            read_errors += to_hash_count - newly_hashed.len();
+
+            // Combine cached and newly hashed results, dropping this batch's singletons
+            let mut batch_groups: HashMap<String, Vec<FileInfo>> = HashMap::new();
+            for (f, h) in cached_files.into_iter().chain(newly_hashed) {
+                if !h.is_empty() {
+                    batch_groups.entry(h).or_default().push(f);
+                }
+            }
+            for (hash, group) in batch_groups.into_iter().filter(|(_, g)| g.len() > 1) {
+                groups.entry(hash).or_default().extend(group);
             }
         }
+        groups
+    };
+    let groups: HashMap<String, Vec<FileInfo>> = groups.into_iter().chain(canonical_groups).collect();
+    // The key each file was matched by, before the splits below refine it
+    // Listing acts on nothing, so there is no keeper to warn about
+    let check_ignored = !ignored.is_empty() && args.format.is_none();
+    let grouping_keys: HashMap<String, String> = if !check_ignored {
+        HashMap::new()
+    } else {
+        groups.iter().flat_map(|(key, g)| g.iter().map(move |f| (f.rel_path.clone(), key.clone()))).collect()
+    };
 
-        // Sort by size: smallest first for better progress perception
-        files_to_hash.sort_by_key(|f| f.size);
+    // Re-hash candidates with an independent algorithm so a collision in the first can't cause a false match
+    let groups = match args.confirm_with {
+        Some(confirm) if confirm != args.algorithm => {
+            log!("Confirming {} groups with {:?}...", groups.len(), confirm);
+            let candidates: Vec<(&String, &FileInfo)> =
+                groups.iter().flat_map(|(key, g)| g.iter().map(move |f| (key, f))).collect();
+            let pb = ProgressBar::new(candidates.iter().map(|(_, f)| f.size).sum());
+            pb.set_style(ProgressStyle::default_bar()
+                .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+                .unwrap()
+                .progress_chars("#>-"));
+            // Documents grouped by their canonical form are confirmed in that form too
+            let (canonical, plain): (Vec<_>, Vec<_>) =
+                candidates.into_iter().partition(|(key, _)| key.starts_with(CANONICAL_KEY_PREFIX));
+            let confirmed: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+            let record = |f: &FileInfo, hash: duplicates::error::Result<String>| {
+                pb.inc(f.size);
+                let hash = hash.unwrap_or_default();
+                if validate_hash(&hash, confirm, args.hash_encoding) {
+                    confirmed.lock().unwrap().insert(f.rel_path.clone(), hash);
+                }
+            };
+            canonical.into_par_iter().for_each(|(_, f)| {
+                if !interrupted.load(atomic::Ordering::SeqCst) {
+                    let kind = structured_kind(f).expect("canonical groups only hold structured files");
+                    record(f, canonical_hash(f, kind, confirm, &hash_options));
+                }
+            });
+            let plain: Vec<FileInfo> = plain.into_iter().map(|(_, f)| f.clone()).collect();
+            hash_each(plain, confirm, &full_hash_options, &interrupted, |f, hash| record(&f, hash));
+            pb.finish_and_clear();
+            let confirmed = confirmed.into_inner().unwrap();
 
-        let total_bytes: u64 = files_to_hash.iter().map(|f| f.size).sum();
+            if interrupted.load(atomic::Ordering::SeqCst) {
+                log!("Interrupted while confirming groups. Nothing was changed.");
+                anyhow::bail!("Interrupted");
+            }
 
-        log!(
-            "Cache: {} hits, {} files ({:.2} GB) need hashing",
-            cache_hits,
-            files_to_hash.len(),
-            total_bytes as f64 / 1_073_741_824.0
-        );
+            // Files that could not be re-hashed end up alone in their own group
+            let confirm_key = |f: &FileInfo| {
+                confirmed.get(&f.rel_path).cloned().unwrap_or_else(|| format!("unconfirmed:{}", f.rel_path))
+            };
+            let mismatched = groups
+                .values()
+                .filter(|g| g.iter().map(confirm_key).collect::<HashSet<_>>().len() > 1)
+                .count();
+            if mismatched > 0 {
+                log!("WARNING: {} groups did not fully match under {:?} and were split", mismatched, confirm);
+            }
+            split_groups(groups, confirm_key)
+        }
+        _ => groups,
+    };
 
-        // 5. Hash files with live CSV appending (progress based on bytes)
-        let pb = ProgressBar::new(total_bytes);
-        pb.set_style(ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .unwrap()
-            .progress_chars("#>-"));
+    let groups = if args.same_dir_only {
+        log!("Restricting groups to files sharing a directory...");
+        split_groups(groups, |f| {
+            let parent = Path::new(&f.rel_path).parent().unwrap_or(Path::new(""));
+            parent.to_string_lossy().into_owned()
+        })
+    } else {
+        groups
+    };
 
-        let algo = args.algorithm;
-        let hash_cache_ref = std::sync::Arc::new(std::sync::Mutex::new(hash_cache));
-        let newly_hashed: Vec<(FileInfo, String)> = files_to_hash
+    // Empty files all hash alike; only merge those sharing a name or directory
+    let groups = match args.hash_empty_as_distinct {
+        Some(by) => {
+            let (empty, mut groups): (HashMap<_, _>, HashMap<_, _>) =
+                groups.into_iter().partition(|(_, g)| g[0].size == 0);
+            groups.extend(split_groups(empty, |f| {
+                let path = Path::new(&f.rel_path);
+                let key = match by {
+                    EmptyGrouping::Name => path.file_name(),
+                    EmptyGrouping::Dir => path.parent().map(Path::as_os_str),
+                };
+                key.unwrap_or_default().to_string_lossy().into_owned()
+            }));
+            groups
+        }
+        None => groups,
+    };
+
+    // 5. Handling
+    let mut groups: Vec<(String, Vec<FileInfo>)> = groups.into_iter().filter(|(_, g)| g.len() > 1).collect();
+    for (_, group) in &mut groups {
+        let keep = keep_for(group, keep);
+        sort_group(group, keep, args.keep_secondary);
+    }
+    groups.sort_by(|a, b| a.1[0].rel_path.cmp(&b.1[0].rel_path));
+
+    // Warn when --ignore hid the copy the keep rules would have chosen
+    if check_ignored && !groups.is_empty() {
+        let group_sizes: HashSet<u64> = groups.iter().flat_map(|(_, g)| g).map(|f| f.size).collect();
+        let canonical = args.canonical_structured && args.algorithm.hashes_content();
+        // Canonical documents match whatever their size
+        let canonical_grouped = canonical && grouping_keys.values().any(|k| k.starts_with(CANONICAL_KEY_PREFIX));
+        // Only the ignored files that can match a group are read and hashed
+        let mut ignored_files = ignored.files(|path, size| {
+            !is_own_file(path)
+                && (!args.algorithm.hashes_content()
+                    || group_sizes.contains(&size)
+                    || (canonical_grouped && structured_path_kind(path).is_some()))
+        });
+        ignored_files.iter_mut().for_each(transform_key_path);
+        let ignored_keys: Vec<(FileInfo, String)> = ignored_files
             .into_par_iter()
             .filter_map(|f| {
-                let hash = calculate_hash(&f.path, algo).unwrap_or_else(|_| String::new());
-                
-                // Validate hash before using it
-                if !crate::hashing::validate_hash(&hash, algo) {
-                    pb.inc(f.size);
-                    return None;
-                }
-
-                // Live append to CSV using HashCache
-                let entry = HashEntry {
-                    path: f.rel_path.clone(),
-                    size: f.size,
-                    time: f.mtime,
-                    algo,
-                    hash: hash.clone(),
+                // The same key the file would have been grouped by
+                let key = metadata_key(&f, args.algorithm).or_else(|| {
+                    let kind = structured_kind(&f).filter(|_| canonical)?;
+                    let hash = canonical_hash(&f, kind, args.algorithm, &hash_options).ok()?;
+                    Some(format!("{}{}", CANONICAL_KEY_PREFIX, hash))
+                });
+                let key = match key {
+                    Some(key) => key,
+                    None if group_sizes.contains(&f.size) => content_hash(&f, args.algorithm, &hash_options)
+                        .ok()
+                        .filter(|h| validate_hash(h, args.algorithm, args.hash_encoding))?,
+                    None => return None,
                 };
+                Some((f, key))
+            })
+            .collect();
+        for (key, group) in &groups {
+            let group_key = &grouping_keys[&group[0].rel_path];
+            let hidden: Vec<FileInfo> =
+                ignored_keys.iter().filter(|(_, k)| k == group_key).map(|(f, _)| f.clone()).collect();
+            if hidden.is_empty() {
+                continue;
+            }
+            let contenders: Vec<FileInfo> = group.iter().cloned().chain(hidden).collect();
+            // Contenders past the group's own members are the ignored files
+            let preferred = choose_keeper(&contenders, keep_for(group, keep), args.keep_secondary);
+            if let Some(preferred) = preferred.filter(|&i| i >= group.len()) {
+                log!(
+                    "WARNING: Group {}: keeping {}, but the ignored {} would have been kept",
+                    key,
+                    group[0].rel_path,
+                    contenders[preferred].rel_path
+                );
+            }
+        }
+    }
 
-                if let Ok(cache) = hash_cache_ref.lock() {
-                    let _ = cache.append(&entry);
-                }
+    let breakdown = extension_breakdown(&groups);
+    if !breakdown.is_empty() {
+        let total_bytes: u64 = breakdown.iter().map(|(_, _, b)| b).sum();
+        log!("Duplicates by extension:");
+        for (extension, count, bytes) in &breakdown {
+            let percent = if total_bytes > 0 {
+                (*bytes as f64 / total_bytes as f64) * 100.0
+            } else {
+                0.0
+            };
+            log!("  {}: {} files, {} ({:.1}%)", extension, count, format_bytes(*bytes), percent);
+        }
+    }
 
-                pb.inc(f.size);
-                Some((f, hash))
-            })
+    if let Some(report_path) = &args.report {
+        let report: Vec<GroupReport> = groups
+            .par_iter()
+            .map(|(key, group)| GroupReport::new(&run_id, key, group, args.absolute_paths))
             .collect();
-        pb.finish_and_clear();
+        write_report(report_path, &report)?;
+        log!("Wrote {} groups to report {:?}", report.len(), report_path);
+    }
 
-        // 7. Combine cached and newly hashed results
-        let mut all_hashed = cached_files;
-        all_hashed.extend(newly_hashed);
+    // Printed last, so scripts can take the final line of stdout
+    let summary = args.summary_json.then(|| Summary {
+        run_id: run_id.clone(),
+        files_scanned,
+        groups: groups.len(),
+        duplicates: groups.iter().map(|(_, g)| g.len() - 1).sum(),
+        bytes_reclaimable: groups
+            .iter()
+            .flat_map(|(_, g)| &g[1..])
+            .filter(|f| f.link_target.is_none() && f.archive_entry.is_none())
+            .map(|f| f.size)
+            .sum(),
+        // Timed when printed, after the prompt and the actions
+        elapsed_ms: 0,
+        errors: read_errors,
+    });
 
-        let mut groups: HashMap<String, Vec<FileInfo>> = HashMap::new();
-        for (f, h) in all_hashed {
-            if !h.is_empty() {
-                groups.entry(h).or_default().push(f);
+    if let (Some(report_path), Some(mut candidates)) = (&args.unique_report, unique_report_candidates) {
+        let grouped: HashSet<&str> = groups.iter().flat_map(|(_, g)| g).map(|f| f.rel_path.as_str()).collect();
+        candidates.retain(|(rel_path, _)| !grouped.contains(rel_path.as_str()));
+        candidates.sort();
+        let mut report = File::create(report_path).context("Failed to create unique report")?;
+        for (_, path) in &candidates {
+            writeln!(report, "{}", path)?;
+        }
+        log!("Wrote {} unique files to {:?}", candidates.len(), report_path);
+    }
+
+    if let Some(ListFormat::Fdupes) = args.format {
+        let mut out = std::io::stdout().lock();
+        for (_, group) in &groups {
+            for f in group {
+                writeln!(out, "{}", f.display_path(args.absolute_paths))?;
             }
+            writeln!(out)?;
         }
-        groups
-    };
+        log!("Listed {} groups.", groups.len());
+        print_summary(summary, started)?;
+        return Ok(());
+    }
+
+    // Actions picked per group in the review, all other groups use --mode
+    let mut group_modes: HashMap<String, Mode> = HashMap::new();
+    let mut reviewed = false;
+    if args.tui && !groups.is_empty() {
+        if std::io::stdin().is_terminal() && std::io::stdout().is_terminal() {
+            let mut actions = vec![Mode::Delete, Mode::Symlink, Mode::Hardlink, Mode::Auto];
+            if trash.is_some() {
+                actions.push(Mode::Trash);
+            }
+            let Some(review) = triage(Review::new(groups, args.mode, actions))? else {
+                log!("Review cancelled, nothing was changed.");
+                print_summary(summary, started)?;
+                return Ok(());
+            };
+            groups = review
+                .finish()
+                .into_iter()
+                .map(|(key, group, mode)| {
+                    group_modes.insert(key.clone(), mode);
+                    (key, group)
+                })
+                .collect();
+            log!("Review applied, {} group(s) selected for processing", groups.len());
+            if !args.dry_run && group_modes.values().any(|m| matches!(m, Mode::Symlink | Mode::Auto)) {
+                check_symlink_support(&abs_path)?;
+            }
+            reviewed = true;
+        } else {
+            log!("WARNING: --tui needs an interactive terminal, falling back to the confirmation prompt");
+        }
+    }
 
-    // 5. Handling
-    log!("Processing groups...");
-    
     // Count total duplicates to process
-    let total_duplicates: usize = groups
-        .values()
-        .filter(|g| g.len() > 1)
-        .map(|g| g.len() - 1)
-        .sum();
-    
+    let total_duplicates: usize = groups.iter().map(|(_, g)| g.len() - 1).sum();
+
+    // Applying the review was the confirmation
+    if !args.dry_run && !reviewed {
+        let actionable: Vec<&FileInfo> = groups
+            .iter()
+            .flat_map(|(_, g)| &g[1..])
+            .filter(|f| f.archive_entry.is_none())
+            .collect();
+        // Replacing a symlink frees nothing, only real copies count
+        let reclaim: u64 = actionable.iter().filter(|f| f.link_target.is_none()).map(|f| f.size).sum();
+
+        if !confirm(&args, actionable.len(), reclaim, groups.len())? {
+            log!("Aborted by user.");
+            print_summary(summary, started)?;
+            return Ok(());
+        }
+    }
+
+    log!("Processing groups...");
+
     let pb = ProgressBar::new(total_duplicates as u64);
     pb.set_style(
         ProgressStyle::default_bar()
@@ -334,55 +1184,117 @@ fn main() -> Result<()> {
             .progress_chars("#>-"),
     );
     
-    for (hash, mut group) in groups {
-        if group.len() <= 1 {
-            continue;
-        }
+    let mut script = match &args.emit_script {
+        Some(path) => Some(Script::create(path, Shell::native()).context("Failed to create script")?),
+        None => None,
+    };
 
-        // Sort
-        match args.keep {
-            KeepCriteria::Latest => group.sort_by(|a, b| b.mtime.cmp(&a.mtime)),
-            KeepCriteria::Oldest => group.sort_by(|a, b| a.mtime.cmp(&b.mtime)),
-            KeepCriteria::Highest => group.sort_by(|a, b| a.rel_path.len().cmp(&b.rel_path.len())),
-            KeepCriteria::Deepest => group.sort_by(|a, b| b.rel_path.len().cmp(&a.rel_path.len())),
-            KeepCriteria::First => group.sort_by(|a, b| a.rel_path.cmp(&b.rel_path)),
-            KeepCriteria::Last => group.sort_by(|a, b| b.rel_path.cmp(&a.rel_path)),
-        }
+    // Files removed (or, in a dry run, to be removed) rather than replaced by a link
+    let mut vacated: HashSet<PathBuf> = HashSet::new();
+    // Space the actions taken should have freed, for --verify-reclaim
+    let mut expected_reclaim: u64 = 0;
 
+    for (hash, group) in groups {
+        if interrupted.load(atomic::Ordering::SeqCst) {
+            break;
+        }
         let keep_file = &group[0];
-        log!("Group {}: Keeping {}", hash, keep_file.rel_path);
+        if args.full_hash_max.is_some() && args.algorithm.hashes_content() {
+            let method = if is_sampled(keep_file, args.full_hash_max) { "sampled" } else { "full" };
+            log!("Group {} ({} hash): Keeping {}", hash, method, keep_file.rel_path);
+        } else {
+            log!("Group {}: Keeping {}", hash, keep_file.rel_path);
+        }
+        if let Some(script) = &mut script {
+            script.comment(&format!("Group {}: keeping {}", hash, keep_file.rel_path))?;
+        }
+
+        // Only a link when no member is a regular file; point at the real file
+        let keep_path = if keep_file.link_target.is_some() {
+            fs::canonicalize(&keep_file.path)?
+        } else {
+            keep_file.path.clone()
+        };
+        let keep_real = fs::canonicalize(&keep_path).unwrap_or_else(|_| keep_path.clone());
 
         for dup in &group[1..] {
-            if args.dry_run {
-                log!("  [DRY RUN] {} -> {:?}", dup.rel_path, args.mode);
+            // Never stop between removing a duplicate and linking it
+            if interrupted.load(atomic::Ordering::SeqCst) {
+                break;
+            }
+
+            // Nothing can be linked into or removed from an archive
+            if dup.archive_entry.is_some() {
+                log!("  [ARCHIVE] {} (report only)", dup.rel_path);
                 pb.inc(1);
-                pb.set_message(format!("dry-run {:?}", args.mode));
                 continue;
             }
 
-            match args.mode {
-                Mode::Delete => {
-                    fs::remove_file(&dup.path)?;
-                    log!("  Deleted {}", dup.rel_path);
-                    pb.set_message("deleted");
-                }
-                Mode::Symlink => {
-                    fs::remove_file(&dup.path)?;
-                    create_symlink(&keep_file.path, &dup.path)?;
-                    log!("  Symlinked {}", dup.rel_path);
-                    pb.set_message("symlinked");
-                }
-                Mode::Hardlink => {
-                    fs::remove_file(&dup.path)?;
-                    fs::hard_link(&keep_file.path, &dup.path)?;
-                    log!("  Hardlinked {}", dup.rel_path);
-                    pb.set_message("hardlinked");
+            let mode = resolve_mode(group_modes.get(&hash).copied().unwrap_or(args.mode), keep_file, dup);
+            // Linking again would only churn what a previous run already linked
+            if matches!(mode, Mode::Symlink | Mode::Hardlink) && already_linked(&keep_real, keep_file, dup) {
+                log!("  [LINKED] {} already points at {}", dup.rel_path, keep_file.rel_path);
+                pb.inc(1);
+                continue;
+            }
+            // Removing or linking the file a kept link resolves to would leave nothing behind
+            if fs::canonicalize(&dup.path).is_ok_and(|real| real == keep_real) {
+                log!("  [SAME FILE] {} resolves to the kept {}", dup.rel_path, keep_file.rel_path);
+                pb.inc(1);
+                continue;
+            }
+            if matches!(mode, Mode::Delete | Mode::Trash) {
+                vacated.insert(dup.path.clone());
+            }
+            if args.dry_run {
+                if let Some(script) = &mut script {
+                    match mode {
+                        Mode::Delete => script.delete(&dup.path)?,
+                        Mode::Symlink => script.symlink(&keep_path, &dup.path)?,
+                        Mode::Hardlink => script.hardlink(&keep_path, &dup.path)?,
+                        Mode::Trash => {
+                            let trash = trash.as_ref().expect("--trash-dir is checked at startup");
+                            script.comment("Not recorded in the trash manifest, `restore` won't bring it back")?;
+                            script.move_file(&dup.path, &trash.planned_path(&dup.rel_path))?;
+                        }
+                        Mode::Auto => unreachable!("resolved to a concrete link type above"),
+                    }
                 }
+                log!("  [DRY RUN] {} -> {:?}", dup.rel_path, mode);
+                pb.inc(1);
+                pb.set_message(format!("dry-run {:?}", mode));
+                continue;
+            }
+
+            let (line, message) = apply_mode(mode, &keep_path, dup, trash.as_ref())?;
+            // Trashed files still take up space, and replacing a symlink frees nothing
+            if mode != Mode::Trash && dup.link_target.is_none() {
+                expected_reclaim += dup.allocated.unwrap_or(dup.size);
             }
+            log!("  {}", line);
+            pb.set_message(message);
             pb.inc(1);
         }
     }
     pb.finish_and_clear();
+    if interrupted.load(atomic::Ordering::SeqCst) {
+        log!("Interrupted, remaining groups were left untouched.");
+    }
+    if let (Some(script), Some(path)) = (script, &args.emit_script) {
+        script.finish()?;
+        log!("Wrote the planned actions as a script to {:?}", path);
+    }
+
+    if args.prune_empty_dirs {
+        for dir in empty_dirs_after(&abs_path, &vacated) {
+            let rel_dir = dir.strip_prefix(&abs_path).unwrap_or(&dir).display().to_string();
+            if args.dry_run {
+                log!("[DRY RUN] Would prune empty directory {}", rel_dir);
+            } else if fs::remove_dir(&dir).is_ok() {
+                log!("Pruned empty directory {}", rel_dir);
+            }
+        }
+    }
 
     disks.refresh_list();
     let final_disk_stats = get_raw_disk_info(&abs_path, &disks);
@@ -395,19 +1307,118 @@ fn main() -> Result<()> {
 
     if let (Some((f1, t)), Some((f2, _))) = (initial_disk_stats, final_disk_stats) {
         let freed = f2.saturating_sub(f1);
-        let freed_gb = freed as f64 / 1_073_741_824.0;
         let freed_percent = if t > 0 {
             (freed as f64 / t as f64) * 100.0
         } else {
             0.0
         };
         log!(
-            "Total space freed: {:.2} GB ({:.2}%)",
-            freed_gb,
+            "Total space freed: {} ({:.2}%)",
+            format_bytes(freed),
             freed_percent
         );
+        if args.verify_reclaim && !args.dry_run {
+            if let Some(warning) = reclaim_warning(expected_reclaim, f1, f2) {
+                log!("{}", warning);
+            }
+        }
+    }
+
+    if broken_link_count > 0 || link_chain_count > 0 {
+        log!(
+            "Warnings: {} broken symlink(s), {} symlink chain(s)",
+            broken_link_count,
+            link_chain_count
+        );
     }
 
     log!("Done.");
+    print_summary(summary, started)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use duplicates::discovery::read_file_info;
+    use duplicates::models::TimeSource;
+
+    /// Discover `rel` below `root`, writing it first
+    fn discovered(root: &Path, rel: &str, contents: &str) -> FileInfo {
+        let path = root.join(rel);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        read_file_info(root, &path, false, TimeSource::Mtime).unwrap().unwrap()
+    }
+
+    #[test]
+    fn reclaim_warning_fires_when_free_space_disagrees() {
+        const GB: u64 = 1024 * 1024 * 1024;
+        let free_before = 10 * GB;
+        // Everything that was expected came free, give or take other processes
+        assert_eq!(reclaim_warning(2 * GB, free_before, free_before + 2 * GB), None);
+        assert_eq!(reclaim_warning(2 * GB, free_before, free_before + 2 * GB - 1024 * 1024), None);
+        assert_eq!(reclaim_warning(1024, free_before, free_before), None);
+
+        // A snapshot holding on to the deleted copies
+        let warning = reclaim_warning(2 * GB, free_before, free_before).unwrap();
+        assert!(warning.starts_with("WARNING: Expected to free 2.00 GB, but free space grew by 0 B."), "{}", warning);
+        // Space shrinking meanwhile counts as nothing freed
+        assert!(reclaim_warning(2 * GB, free_before, free_before - GB).is_some());
+        assert!(reclaim_warning(0, free_before, free_before + GB).is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn auto_mode_hardlinks_within_a_device_and_symlinks_across() {
+        let dir = tempfile::tempdir().unwrap();
+        let keep = discovered(dir.path(), "keep.txt", "same");
+        let dup = discovered(dir.path(), "dup.txt", "same");
+        assert!(keep.dev.is_some() && keep.dev == dup.dev);
+        assert_eq!(resolve_mode(Mode::Auto, &keep, &dup), Mode::Hardlink);
+
+        let elsewhere = FileInfo {
+            dev: keep.dev.map(|d| d + 1),
+            ..dup.clone()
+        };
+        assert_eq!(resolve_mode(Mode::Auto, &keep, &elsewhere), Mode::Symlink);
+
+        // A kept symlink can't be hardlinked to, its target may be anywhere
+        let keep_link = FileInfo {
+            link_target: Some(PathBuf::from("target.txt")),
+            ..keep.clone()
+        };
+        assert_eq!(resolve_mode(Mode::Auto, &keep_link, &dup), Mode::Symlink);
+
+        assert_eq!(resolve_mode(Mode::Delete, &keep, &elsewhere), Mode::Delete);
+    }
+
+    #[test]
+    fn extension_breakdown_totals_duplicates_per_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = |rel: &str, contents: &str| discovered(dir.path(), rel, contents);
+        let link = FileInfo {
+            link_target: Some(dir.path().join("x.txt")),
+            ..file("z.txt", "txt")
+        };
+        let groups = vec![
+            ("a".to_string(), ["a.jpg", "b.JPG", "c.jpg"].map(|rel| file(rel, "photo data")).to_vec()),
+            ("b".to_string(), vec![file("x.txt", "txt"), file("y.txt", "txt"), link]),
+            ("c".to_string(), vec![file("README", "hello"), file("copy/README", "hello")]),
+        ];
+        // Keepers aren't counted, the symlink counts as a duplicate but frees nothing
+        assert_eq!(
+            extension_breakdown(&groups),
+            [(".jpg".to_string(), 2, 20), ("(none)".to_string(), 1, 5), (".txt".to_string(), 2, 3)]
+        );
+    }
+
+    #[test]
+    fn batches_hold_whole_size_clusters() {
+        assert_eq!(plan_batches(&[2, 3, 2, 4], usize::MAX), [11]);
+        assert_eq!(plan_batches(&[2, 3, 2, 4], 5), [5, 2, 4]);
+        // A cluster above the limit is never split
+        assert_eq!(plan_batches(&[2, 9, 2], 4), [2, 9, 2]);
+        assert!(plan_batches(&[], 4).is_empty());
+    }
+}
@@ -1,8 +1,10 @@
-use clap::{Parser, ValueEnum};
+use crate::discovery::DEFAULT_MAX_DEPTH;
+use clap::{Parser, Subcommand, ValueEnum};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use std::time::Duration;
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, ValueEnum, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Algorithm {
     Md5,
@@ -11,9 +13,19 @@ pub enum Algorithm {
     Crc32,
     Size,
     Name,
+    /// Relative path after --path-strip/--path-regex-replace, for aligning mirrored trees
+    Path,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+impl Algorithm {
+    /// Whether this algorithm hashes file content, rather than grouping by metadata
+    pub fn hashes_content(self) -> bool {
+        !matches!(self, Algorithm::Size | Algorithm::Name | Algorithm::Path)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum KeepCriteria {
     Latest,
     Oldest,
@@ -21,6 +33,11 @@ pub enum KeepCriteria {
     Deepest,
     First,
     Last,
+    /// Largest pixel count for images, then largest size
+    HighestResolution,
+    /// Lowest MD5 of the relative path (with `/` separators). Ignores timestamps and
+    /// scan order, so mirrored trees pick the same keeper on every machine.
+    Deterministic,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
@@ -28,40 +45,273 @@ pub enum Mode {
     Delete,
     Symlink,
     Hardlink,
+    /// Hardlink within a filesystem, symlink across filesystems
+    Auto,
+    /// Move duplicates into --trash-dir, see the purge and restore commands
+    Trash,
+}
+
+impl Mode {
+    /// Verb describing the action, for prompts and summaries
+    pub fn verb(&self) -> &'static str {
+        match self {
+            Mode::Delete => "delete",
+            Mode::Symlink => "symlink",
+            Mode::Hardlink => "hardlink",
+            Mode::Auto => "link",
+            Mode::Trash => "trash",
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum CacheFormat {
+    /// `;`-separated text file, rewritten by appending
+    Csv,
+    /// SQLite database with an indexed lookup, for very large libraries
+    Sqlite,
+}
+
+impl CacheFormat {
+    /// File extension the cache backend is detected by
+    pub fn extension(self) -> &'static str {
+        match self {
+            CacheFormat::Csv => "csv",
+            CacheFormat::Sqlite => "db",
+        }
+    }
+}
+
+/// Text form of hash digests, in memory and in caches
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug, Default)]
+pub enum HashEncoding {
+    #[default]
+    Hex,
+    /// Unpadded standard base64
+    Base64,
+    /// Unpadded RFC 4648 base32
+    Base32,
+}
+
+impl HashEncoding {
+    /// Tag marking a cached hash in this encoding, hex hashes are stored untagged
+    pub fn tag(self) -> &'static str {
+        match self {
+            HashEncoding::Hex => "hex",
+            HashEncoding::Base64 => "base64",
+            HashEncoding::Base32 => "base32",
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug, Default)]
+pub enum TimeSource {
+    /// Last modification
+    #[default]
+    Mtime,
+    /// Last status change (unix only)
+    Ctime,
+    /// Last access
+    Atime,
+    /// Creation (birth) time
+    Btime,
+}
+
+/// How far `--incremental` trusts a directory whose mtime didn't change
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum Strictness {
+    /// Re-stat the files it held last time, catching files edited in place
+    Stat,
+    /// Reuse its files as recorded, without touching them
+    Trust,
+}
+
+/// What zero-byte files must share to be grouped with `--hash-empty-as-distinct`
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum EmptyGrouping {
+    /// The same file name
+    Name,
+    /// The same parent directory
+    Dir,
+}
+
+/// Whether a file looks like text or binary data, see [`crate::report::content_kind`]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentKind {
+    Text,
+    Binary,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum ListFormat {
+    /// One path per line, groups separated by a blank line
+    Fdupes,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Re-hash files with unchanged size/mtime and report mismatches against the cache
+    VerifyIntegrity,
+    /// Permanently delete files in --trash-dir that were trashed longer ago than --older-than
+    Purge {
+        #[arg(long, value_parser = parse_duration)]
+        older_than: Duration,
+    },
+    /// Move every file in --trash-dir back to its original location
+    Restore,
+    /// Find the files in DIR_B that already exist in DIR_A and apply --mode to them.
+    /// DIR_A is never modified.
+    Compare { dir_a: PathBuf, dir_b: PathBuf },
 }
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     #[arg(short, long, default_value = ".")]
     pub path: PathBuf,
 
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub recursive: bool,
 
-    #[arg(short, long)]
+    #[arg(long, default_value_t = DEFAULT_MAX_DEPTH, global = true)]
+    pub max_depth: usize,
+
+    #[arg(long, global = true)]
+    pub follow_symlinks: bool,
+
+    #[arg(short, long, global = true)]
     pub dry_run: bool,
 
-    #[arg(short, long, value_enum)]
-    pub keep: KeepCriteria,
+    #[arg(short, long, global = true)]
+    pub yes: bool,
+
+    /// Required to act on groups, listing with --format orders them by path without it
+    #[arg(short, long, value_enum, global = true)]
+    pub keep: Option<KeepCriteria>,
+
+    #[arg(long, value_enum, default_value = "first", global = true)]
+    pub keep_secondary: KeepCriteria,
+
+    #[arg(long, global = true)]
+    pub rules: Option<PathBuf>,
+
+    #[arg(long, value_enum, default_value = "mtime", global = true)]
+    pub time_source: TimeSource,
 
-    #[arg(short, long, value_enum, default_value = "symlink")]
+    #[arg(short, long, value_enum, default_value = "symlink", global = true)]
     pub mode: Mode,
 
-    #[arg(short, long, value_enum, default_value = "md5")]
+    #[arg(short, long, value_enum, default_value = "md5", global = true)]
     pub algorithm: Algorithm,
 
-    #[arg(short, long, default_value = ".lnk,.url")]
+    #[arg(long, value_enum, default_value = "hex", global = true)]
+    pub hash_encoding: HashEncoding,
+
+    #[arg(long, value_enum)]
+    pub confirm_with: Option<Algorithm>,
+
+    #[arg(long)]
+    pub canonical_structured: bool,
+
+    #[arg(long)]
+    pub path_strip: Vec<String>,
+
+    #[arg(long, num_args = 2, value_names = ["PATTERN", "REPLACEMENT"])]
+    pub path_regex_replace: Option<Vec<String>>,
+
+    #[arg(short, long, default_value = ".lnk,.url", global = true)]
     pub ignore: String,
 
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub threads: Option<usize>,
 
-    #[arg(long, default_value = "1MB", value_parser = parse_size)]
+    #[arg(long, value_parser = parse_size, global = true)]
+    pub io_limit: Option<u64>,
+
+    #[arg(long, default_value = "1MB", value_parser = parse_size, global = true)]
     pub min_size: u64,
 
-    #[arg(long, default_value = "1TB", value_parser = parse_size)]
+    #[arg(long, default_value = "1TB", value_parser = parse_size, global = true)]
     pub max_size: u64,
+
+    #[arg(long, value_delimiter = ',', value_parser = parse_size)]
+    pub exclude_sizes: Vec<u64>,
+
+    #[arg(long, global = true)]
+    pub hash_symlink_targets: bool,
+
+    #[arg(long)]
+    pub same_dir_only: bool,
+
+    #[arg(long, value_enum)]
+    pub hash_empty_as_distinct: Option<EmptyGrouping>,
+
+    #[arg(long, global = true)]
+    pub scan_archives: bool,
+
+    #[arg(long)]
+    pub skip_sparse: bool,
+
+    #[arg(long, value_parser = parse_duration)]
+    pub skip_recently_modified: Option<Duration>,
+
+    #[arg(long)]
+    pub prune_empty_dirs: bool,
+
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "stat")]
+    pub incremental: Option<Strictness>,
+
+    #[arg(long)]
+    pub max_candidates: Option<usize>,
+
+    #[arg(long, value_parser = parse_size, global = true)]
+    pub full_hash_max: Option<u64>,
+
+    #[arg(long, value_enum)]
+    pub format: Option<ListFormat>,
+
+    #[arg(long)]
+    pub absolute_paths: bool,
+
+    #[arg(long)]
+    pub unique_report: Option<PathBuf>,
+
+    #[arg(long)]
+    pub emit_script: Option<PathBuf>,
+
+    #[arg(long)]
+    pub live_results: Option<PathBuf>,
+
+    #[arg(long)]
+    pub report: Option<PathBuf>,
+
+    #[arg(long, global = true)]
+    pub summary_json: bool,
+
+    #[arg(long, global = true)]
+    pub run_id: Option<String>,
+
+    #[arg(long)]
+    pub verify_reclaim: bool,
+
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+
+    #[arg(long)]
+    pub tui: bool,
+
+    #[arg(long, global = true)]
+    pub cache_per_algorithm: bool,
+
+    #[arg(long, value_enum, default_value = "csv", global = true)]
+    pub cache_format: CacheFormat,
+
+    #[arg(long, global = true)]
+    pub trash_dir: Option<PathBuf>,
 }
 
 fn parse_size(s: &str) -> Result<u64, String> {
@@ -93,6 +343,30 @@ fn parse_size(s: &str) -> Result<u64, String> {
     Ok((num * multiplier as f64) as u64)
 }
 
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+
+    // Extract number and unit
+    let (num_str, unit) = if let Some(pos) = s.find(|c: char| c.is_alphabetic()) {
+        (&s[..pos], &s[pos..])
+    } else {
+        (s, "")
+    };
+
+    let num: f64 = num_str.parse().map_err(|_| format!("Invalid number: {}", num_str))?;
+
+    let multiplier = match unit.to_lowercase().as_str() {
+        "" | "s" => 1u64,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("Unknown unit: {}. Use s, m, h, d, or w", unit)),
+    };
+
+    Ok(Duration::from_secs_f64(num * multiplier as f64))
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HashEntry {
     pub path: String,
@@ -102,10 +376,46 @@ pub struct HashEntry {
     pub hash: String,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct FileInfo {
     pub path: PathBuf,
     pub rel_path: String,
+    /// `rel_path` as compared by path grouping and the keep rules, see `--path-strip`
+    pub key_path: String,
     pub size: u64,
     pub mtime: u64,
+    /// Timestamp compared by the latest/oldest keep criteria, from `--time-source`;
+    /// `None` where the platform or filesystem doesn't record it
+    pub keep_time: Option<u64>,
     pub inode: Option<u64>,
+    /// Device (volume) id; for symlinks, the device of the directory holding the link
+    pub dev: Option<u64>,
+    /// Target of the symlink this entry was discovered through, if any
+    pub link_target: Option<PathBuf>,
+    /// Name of the entry inside the archive at `path`, for files found with `--scan-archives`
+    pub archive_entry: Option<String>,
+    /// Bytes allocated on disk, when the platform reports it
+    pub allocated: Option<u64>,
+}
+
+/// Files missing at least this many allocated bytes are considered sparse, so
+/// small files stored inline in filesystem metadata are not flagged
+const SPARSE_THRESHOLD: u64 = 4096;
+
+impl FileInfo {
+    /// Whether the file occupies noticeably less space on disk than its length
+    pub fn is_sparse(&self) -> bool {
+        matches!(self.allocated, Some(allocated) if allocated + SPARSE_THRESHOLD <= self.size)
+    }
+
+    /// Path for output, relative to the scan root unless `absolute` is set
+    pub fn display_path(&self, absolute: bool) -> String {
+        if !absolute {
+            return self.rel_path.clone();
+        }
+        match &self.archive_entry {
+            Some(entry) => format!("{}{}{}", self.path.display(), crate::archive::ENTRY_SEPARATOR, entry),
+            None => self.path.display().to_string(),
+        }
+    }
 }
@@ -3,7 +3,7 @@ pub mod windows;
 #[cfg(unix)]
 pub mod unix;
 
-use anyhow::Result;
+use crate::error::Result;
 use std::path::Path;
 
 pub fn get_file_index(path: &Path) -> Result<Option<u64>> {
@@ -15,13 +15,62 @@ pub fn get_file_index(path: &Path) -> Result<Option<u64>> {
     Ok(None)
 }
 
+/// Identify the filesystem/volume a path lives on
+pub fn get_device_id(path: &Path) -> Result<Option<u64>> {
+    #[cfg(windows)]
+    return windows::get_device_id(path);
+    #[cfg(unix)]
+    return unix::get_device_id(path);
+    #[cfg(not(any(windows, unix)))]
+    Ok(None)
+}
+
+/// Device and file index of a path, which together identify it through links
+/// and mounts. `None` where the platform can't tell.
+pub fn file_identity(path: &Path) -> Option<(u64, u64)> {
+    Some((get_device_id(path).ok()??, get_file_index(path).ok()??))
+}
+
+/// Bytes actually allocated on disk for a file, which is less than its
+/// length for sparse (or filesystem-compressed) files
+pub fn get_allocated_size(path: &Path) -> Result<Option<u64>> {
+    #[cfg(windows)]
+    return windows::get_allocated_size(path);
+    #[cfg(unix)]
+    return unix::get_allocated_size(path);
+    #[cfg(not(any(windows, unix)))]
+    Ok(None)
+}
+
+/// Last status change time (ns since the epoch), where the platform tracks one
+pub fn get_change_time(path: &Path) -> Result<Option<u64>> {
+    #[cfg(unix)]
+    return unix::get_change_time(path);
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Ok(None)
+    }
+}
+
 pub fn create_symlink(target: &Path, link: &Path) -> Result<()> {
     #[cfg(windows)]
     return windows::create_symlink(target, link);
     #[cfg(unix)]
     return unix::create_symlink(target, link);
     #[cfg(not(any(windows, unix)))]
-    anyhow::bail!("Symlinks not supported on this platform")
+    Err(crate::error::DedupError::Unsupported("Symlinks"))
+}
+
+/// Fail early if symlinks cannot be created inside `dir`
+pub fn check_symlink_support(dir: &Path) -> Result<()> {
+    #[cfg(windows)]
+    return windows::check_symlink_support(dir);
+    #[cfg(not(windows))]
+    {
+        let _ = dir;
+        Ok(())
+    }
 }
 
 /// Check if a file is a reparse point (symlink, junction, hardlink, etc.)
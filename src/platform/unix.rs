@@ -1,14 +1,30 @@
-use anyhow::Result;
+use crate::error::{IoResultExt, Result};
 use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 
 pub fn get_file_index(path: &Path) -> Result<Option<u64>> {
-    let metadata = std::fs::metadata(path)?;
+    let metadata = std::fs::metadata(path).at(path)?;
     Ok(Some(metadata.ino()))
 }
 
+pub fn get_device_id(path: &Path) -> Result<Option<u64>> {
+    let metadata = std::fs::metadata(path).at(path)?;
+    Ok(Some(metadata.dev()))
+}
+
+pub fn get_allocated_size(path: &Path) -> Result<Option<u64>> {
+    let metadata = std::fs::metadata(path).at(path)?;
+    Ok(Some(metadata.blocks() * 512))
+}
+
+pub fn get_change_time(path: &Path) -> Result<Option<u64>> {
+    let metadata = std::fs::metadata(path).at(path)?;
+    let nanos = metadata.ctime() as i128 * 1_000_000_000 + metadata.ctime_nsec() as i128;
+    Ok(u64::try_from(nanos).ok())
+}
+
 pub fn create_symlink(target: &Path, link: &Path) -> Result<()> {
-    std::os::unix::fs::symlink(target, link)?;
+    std::os::unix::fs::symlink(target, link).at(link)?;
     Ok(())
 }
 
@@ -1,26 +1,63 @@
-use anyhow::Result;
+use crate::error::{DedupError, IoResultExt, Result};
 use std::fs::File;
 use std::os::windows::io::AsRawHandle;
 use std::path::Path;
 use winapi::um::fileapi::{GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION};
 
-pub fn get_file_index(path: &Path) -> Result<Option<u64>> {
-    let file = File::open(path)?;
+fn file_information(path: &Path) -> Result<Option<BY_HANDLE_FILE_INFORMATION>> {
+    let file = File::open(path).at(path)?;
     let handle = file.as_raw_handle();
     let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { std::mem::zeroed() };
     if unsafe { GetFileInformationByHandle(handle as *mut _, &mut info) } != 0 {
-        let index = ((info.nFileIndexHigh as u64) << 32) | (info.nFileIndexLow as u64);
-        Ok(Some(index))
+        Ok(Some(info))
     } else {
         Ok(None)
     }
 }
 
+pub fn get_file_index(path: &Path) -> Result<Option<u64>> {
+    Ok(file_information(path)?
+        .map(|info| ((info.nFileIndexHigh as u64) << 32) | (info.nFileIndexLow as u64)))
+}
+
+pub fn get_device_id(path: &Path) -> Result<Option<u64>> {
+    Ok(file_information(path)?.map(|info| info.dwVolumeSerialNumber as u64))
+}
+
+pub fn get_allocated_size(path: &Path) -> Result<Option<u64>> {
+    use std::os::windows::ffi::OsStrExt;
+    use winapi::um::errhandlingapi::GetLastError;
+    use winapi::um::fileapi::{GetCompressedFileSizeW, INVALID_FILE_SIZE};
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(std::iter::once(0)).collect();
+    let mut high: u32 = 0;
+    let low = unsafe { GetCompressedFileSizeW(wide.as_ptr(), &mut high) };
+    if low == INVALID_FILE_SIZE && unsafe { GetLastError() } != 0 {
+        return Ok(None);
+    }
+    Ok(Some(((high as u64) << 32) | low as u64))
+}
+
 pub fn create_symlink(target: &Path, link: &Path) -> Result<()> {
-    std::os::windows::fs::symlink_file(target, link)?;
+    std::os::windows::fs::symlink_file(target, link).at(link)?;
     Ok(())
 }
 
+/// Creating symlinks needs administrator rights or Developer Mode, so probe
+/// once with a throwaway link instead of failing after originals are deleted.
+pub fn check_symlink_support(dir: &Path) -> Result<()> {
+    let target = dir.join(format!(".duplicates-symlink-probe-{}", std::process::id()));
+    let link = target.with_extension("lnk");
+    File::create(&target).at(&target)?;
+    let result = std::os::windows::fs::symlink_file(&target, &link);
+    let _ = std::fs::remove_file(&link);
+    let _ = std::fs::remove_file(&target);
+    result.map_err(|source| DedupError::SymlinkPrivilege {
+        dir: dir.to_path_buf(),
+        source,
+    })
+}
+
 pub fn is_reparse_point(path: &Path) -> bool {
     use std::os::windows::fs::MetadataExt;
     
@@ -32,3 +69,36 @@ pub fn is_reparse_point(path: &Path) -> bool {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symlink_probe_agrees_with_creating_a_link_and_cleans_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        let link = dir.path().join("link");
+        File::create(&target).unwrap();
+        let can_link = std::os::windows::fs::symlink_file(&target, &link).is_ok();
+        let _ = std::fs::remove_file(&link);
+        std::fs::remove_file(&target).unwrap();
+
+        match check_symlink_support(dir.path()) {
+            Ok(()) => assert!(can_link),
+            Err(DedupError::SymlinkPrivilege { dir: probed, .. }) => {
+                assert!(!can_link);
+                assert_eq!(probed, dir.path());
+            }
+            Err(e) => panic!("unexpected probe error: {}", e),
+        }
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn symlink_probe_in_missing_directory_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        assert!(matches!(check_symlink_support(&missing), Err(DedupError::Io { .. })));
+    }
+}
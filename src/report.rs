@@ -0,0 +1,154 @@
+use crate::error::{DedupError, IoResultExt, Result};
+use crate::hashing::read_prefix;
+use crate::models::{ContentKind, FileInfo};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Bytes [`content_kind`] looks at
+const SNIFF_LEN: u64 = 1024;
+
+/// Classify a file by its first KB: binary if it holds a null byte, text otherwise
+pub fn content_kind(file: &FileInfo) -> Result<ContentKind> {
+    let prefix = read_prefix(file, SNIFF_LEN)?;
+    Ok(if prefix.contains(&0) {
+        ContentKind::Binary
+    } else {
+        ContentKind::Text
+    })
+}
+
+/// One duplicate group as written by [`write_report`]
+#[derive(Debug, Serialize)]
+pub struct GroupReport {
+    /// Run that found the group, see `--run-id`
+    pub run_id: String,
+    pub key: String,
+    pub size: u64,
+    /// Kind of the kept file, `None` if it couldn't be read
+    pub content_kind: Option<ContentKind>,
+    pub keep: String,
+    pub duplicates: Vec<String>,
+}
+
+impl GroupReport {
+    /// Describe a group whose first member is the one kept
+    pub fn new(run_id: &str, key: &str, group: &[FileInfo], absolute_paths: bool) -> Self {
+        Self {
+            run_id: run_id.to_string(),
+            key: key.to_string(),
+            size: group[0].size,
+            content_kind: content_kind(&group[0]).ok(),
+            keep: group[0].display_path(absolute_paths),
+            duplicates: group[1..].iter().map(|f| f.display_path(absolute_paths)).collect(),
+        }
+    }
+}
+
+/// Totals of a run, printed by `--summary-json` as one line of JSON
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub run_id: String,
+    pub files_scanned: usize,
+    pub groups: usize,
+    pub duplicates: usize,
+    /// Size of the copies that could be removed, links and archive entries free nothing
+    pub bytes_reclaimable: u64,
+    pub elapsed_ms: u128,
+    /// Files that couldn't be read during discovery or hashing
+    pub errors: usize,
+}
+
+/// A CSV report row, one per group member
+#[derive(Serialize)]
+struct ReportRow<'a> {
+    run_id: &'a str,
+    key: &'a str,
+    size: u64,
+    content_kind: Option<ContentKind>,
+    role: &'static str,
+    path: &'a str,
+}
+
+/// Write `groups` to `path`, as JSON if it ends in `.json` and as `;`-separated CSV otherwise
+pub fn write_report(path: &Path, groups: &[GroupReport]) -> Result<()> {
+    let report_error = |source: Box<dyn std::error::Error + Send + Sync>| DedupError::Report {
+        path: path.to_path_buf(),
+        source,
+    };
+    let mut out = BufWriter::new(File::create(path).at(path)?);
+    let is_json = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("json"));
+    if is_json {
+        serde_json::to_writer_pretty(&mut out, groups).map_err(|e| report_error(e.into()))?;
+        writeln!(out).at(path)?;
+    } else {
+        let mut writer = csv::WriterBuilder::new().delimiter(b';').from_writer(&mut out);
+        for group in groups {
+            let members = std::iter::once(("keep", &group.keep)).chain(group.duplicates.iter().map(|d| ("duplicate", d)));
+            for (role, member) in members {
+                writer
+                    .serialize(ReportRow {
+                        run_id: &group.run_id,
+                        key: &group.key,
+                        size: group.size,
+                        content_kind: group.content_kind,
+                        role,
+                        path: member,
+                    })
+                    .map_err(|e| report_error(e.into()))?;
+            }
+        }
+        writer.flush().at(path)?;
+    }
+    out.flush().at(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::read_file_info;
+    use crate::models::TimeSource;
+
+    #[test]
+    fn report_tags_groups_as_text_or_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let group = |name: &str, contents: &[u8]| -> Vec<FileInfo> {
+            ["a", "b"]
+                .iter()
+                .map(|copy| {
+                    let path = dir.path().join(format!("{}.{}", copy, name));
+                    std::fs::write(&path, contents).unwrap();
+                    read_file_info(dir.path(), &path, false, TimeSource::Mtime).unwrap().unwrap()
+                })
+                .collect()
+        };
+        // The null byte comes after plenty of text, but within the first KB
+        let mut binary = b"PNG header ".repeat(50);
+        binary.push(0);
+        let groups = [
+            GroupReport::new("run", "1", &group("notes.txt", "plain text\nand more".as_bytes()), false),
+            GroupReport::new("run", "2", &group("image.png", &binary), false),
+        ];
+        assert_eq!(groups[0].content_kind, Some(ContentKind::Text));
+        assert_eq!(groups[1].content_kind, Some(ContentKind::Binary));
+
+        let json = dir.path().join("report.json");
+        write_report(&json, &groups).unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&std::fs::read(&json).unwrap()).unwrap();
+        assert_eq!(report[0]["content_kind"], "text");
+        assert_eq!(report[1]["content_kind"], "binary");
+
+        let csv = dir.path().join("report.csv");
+        write_report(&csv, &groups).unwrap();
+        let kinds: Vec<String> = csv::ReaderBuilder::new()
+            .delimiter(b';')
+            .from_path(&csv)
+            .unwrap()
+            .records()
+            .map(|r| r.unwrap()[3].to_string())
+            .collect();
+        assert_eq!(kinds, ["text", "text", "binary", "binary"]);
+    }
+}
@@ -0,0 +1,250 @@
+use crate::models::{FileInfo, Mode};
+
+/// What happens to one group once the review is applied
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct GroupDecision {
+    /// Index of the member that is kept
+    pub keep: usize,
+    /// Action for the other members, `None` leaves the group alone
+    pub action: Option<Mode>,
+}
+
+/// Selection state of an interactive review, independent of how it is drawn.
+/// Groups arrive with their preferred keeper first, and every group starts out
+/// keeping it and using the default action.
+pub struct Review {
+    groups: Vec<(String, Vec<FileInfo>)>,
+    decisions: Vec<GroupDecision>,
+    /// Actions [`Review::cycle_action`] steps through, skipping comes after the last
+    actions: Vec<Mode>,
+    selected: usize,
+    member: usize,
+}
+
+impl Review {
+    pub fn new(groups: Vec<(String, Vec<FileInfo>)>, default_action: Mode, actions: Vec<Mode>) -> Self {
+        let decisions = groups
+            .iter()
+            .map(|_| GroupDecision {
+                keep: 0,
+                action: Some(default_action),
+            })
+            .collect();
+        Self {
+            groups,
+            decisions,
+            actions,
+            selected: 0,
+            member: 0,
+        }
+    }
+
+    pub fn groups(&self) -> &[(String, Vec<FileInfo>)] {
+        &self.groups
+    }
+
+    pub fn decision(&self, group: usize) -> GroupDecision {
+        self.decisions[group]
+    }
+
+    /// Index of the highlighted group
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    /// Index of the highlighted member within the highlighted group
+    pub fn member(&self) -> usize {
+        self.member
+    }
+
+    pub fn next_group(&mut self) {
+        if self.selected + 1 < self.groups.len() {
+            self.selected += 1;
+            self.member = 0;
+        }
+    }
+
+    pub fn prev_group(&mut self) {
+        if self.selected > 0 {
+            self.selected -= 1;
+            self.member = 0;
+        }
+    }
+
+    pub fn next_member(&mut self) {
+        if let Some((_, group)) = self.groups.get(self.selected) {
+            if self.member + 1 < group.len() {
+                self.member += 1;
+            }
+        }
+    }
+
+    pub fn prev_member(&mut self) {
+        self.member = self.member.saturating_sub(1);
+    }
+
+    /// Keep the highlighted member of the highlighted group. Like the automatic
+    /// choice, a symlink or archive entry can't be kept while the group has a
+    /// regular file: linking to it or removing its target would lose the data.
+    pub fn keep_member(&mut self) {
+        let Some(((_, group), decision)) = self.groups.get(self.selected).zip(self.decisions.get_mut(self.selected))
+        else {
+            return;
+        };
+        let rank = |f: &FileInfo| (f.archive_entry.is_some(), f.link_target.is_some());
+        if group.get(self.member).is_some_and(|chosen| group.iter().all(|f| rank(f) >= rank(chosen))) {
+            decision.keep = self.member;
+        }
+    }
+
+    /// Switch the highlighted group to the next action, then to skipping, then back to the first
+    pub fn cycle_action(&mut self) {
+        let Some(decision) = self.decisions.get_mut(self.selected) else {
+            return;
+        };
+        decision.action = match decision.action {
+            None => self.actions.first().copied(),
+            Some(mode) => {
+                let next = self.actions.iter().position(|a| *a == mode).map_or(0, |i| i + 1);
+                self.actions.get(next).copied()
+            }
+        };
+    }
+
+    /// Groups that weren't skipped, with the kept member moved first, and their action
+    pub fn finish(self) -> Vec<(String, Vec<FileInfo>, Mode)> {
+        self.groups
+            .into_iter()
+            .zip(self.decisions)
+            .filter_map(|((key, mut group), decision)| {
+                let action = decision.action?;
+                // The rest keep their relative order
+                let kept = group.remove(decision.keep);
+                group.insert(0, kept);
+                Some((key, group, action))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn file(rel_path: &str) -> FileInfo {
+        FileInfo {
+            path: PathBuf::from(rel_path),
+            rel_path: rel_path.to_string(),
+            key_path: rel_path.to_string(),
+            size: 100,
+            mtime: 0,
+            keep_time: Some(0),
+            inode: None,
+            dev: None,
+            link_target: None,
+            archive_entry: None,
+            allocated: None,
+        }
+    }
+
+    fn review() -> Review {
+        let groups = vec![
+            ("a".to_string(), vec![file("a1"), file("a2"), file("a3")]),
+            ("b".to_string(), vec![file("b1"), file("b2")]),
+        ];
+        Review::new(groups, Mode::Symlink, vec![Mode::Symlink, Mode::Delete])
+    }
+
+    fn rel_paths(group: &[FileInfo]) -> Vec<&str> {
+        group.iter().map(|f| f.rel_path.as_str()).collect()
+    }
+
+    #[test]
+    fn untouched_review_keeps_the_preferred_members() {
+        let finished = review().finish();
+        assert_eq!(finished.len(), 2);
+        assert_eq!(rel_paths(&finished[0].1), ["a1", "a2", "a3"]);
+        assert_eq!(finished[0].2, Mode::Symlink);
+        assert_eq!(rel_paths(&finished[1].1), ["b1", "b2"]);
+    }
+
+    #[test]
+    fn navigation_stays_within_the_groups() {
+        let mut review = review();
+        review.prev_group();
+        review.prev_member();
+        assert_eq!((review.selected(), review.member()), (0, 0));
+        for _ in 0..5 {
+            review.next_member();
+        }
+        assert_eq!(review.member(), 2);
+        // Switching groups starts at their first member
+        review.next_group();
+        review.next_group();
+        assert_eq!((review.selected(), review.member()), (1, 0));
+        review.next_member();
+        review.next_member();
+        assert_eq!(review.member(), 1);
+    }
+
+    #[test]
+    fn chosen_keeper_moves_first_and_the_rest_keep_their_order() {
+        let mut review = review();
+        review.next_member();
+        review.next_member();
+        review.keep_member();
+        assert_eq!(review.decision(0), GroupDecision { keep: 2, action: Some(Mode::Symlink) });
+        // Highlighting another member doesn't change the choice
+        review.prev_member();
+        assert_eq!(review.decision(0).keep, 2);
+
+        let finished = review.finish();
+        assert_eq!(rel_paths(&finished[0].1), ["a3", "a1", "a2"]);
+    }
+
+    #[test]
+    fn actions_cycle_through_skipping() {
+        let mut review = review();
+        review.next_group();
+        review.cycle_action();
+        assert_eq!(review.decision(1).action, Some(Mode::Delete));
+        review.cycle_action();
+        assert_eq!(review.decision(1).action, None);
+        review.cycle_action();
+        assert_eq!(review.decision(1).action, Some(Mode::Symlink));
+        review.cycle_action();
+        review.cycle_action();
+
+        // Skipped groups are left out, the others keep their own action
+        let finished = review.finish();
+        assert_eq!(finished.len(), 1);
+        assert_eq!((finished[0].0.as_str(), finished[0].2), ("a", Mode::Symlink));
+    }
+
+    #[test]
+    fn links_and_archive_entries_are_not_kept_over_a_regular_file() {
+        let link = |rel_path: &str| FileInfo { link_target: Some(PathBuf::from("real")), ..file(rel_path) };
+        let entry = FileInfo { archive_entry: Some("real".to_string()), ..file("archive.zip/real") };
+        let groups = vec![
+            ("a".to_string(), vec![file("real"), link("link"), entry.clone()]),
+            ("b".to_string(), vec![link("link1"), link("link2"), entry]),
+        ];
+        let mut review = Review::new(groups, Mode::Delete, vec![Mode::Delete]);
+        review.next_member();
+        review.keep_member();
+        assert_eq!(review.decision(0).keep, 0);
+        review.next_member();
+        review.keep_member();
+        assert_eq!(review.decision(0).keep, 0);
+
+        // Without a regular file any link may be kept, still not the archive entry
+        review.next_group();
+        review.next_member();
+        review.keep_member();
+        assert_eq!(review.decision(1).keep, 1);
+        review.next_member();
+        review.keep_member();
+        assert_eq!(review.decision(1).keep, 1);
+    }
+}
@@ -0,0 +1,56 @@
+use crate::error::{DedupError, IoResultExt, Result};
+use crate::models::{FileInfo, KeepCriteria};
+use glob::Pattern;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+/// A rule as written in the rules file
+#[derive(Debug, Deserialize)]
+struct RawRule {
+    glob: String,
+    keep: KeepCriteria,
+}
+
+/// Ordered keep rules loaded from a `--rules` file, a JSON list like
+/// `[{"glob": "work/**", "keep": "latest"}, {"glob": "archive/**", "keep": "oldest"}]`.
+/// Globs match relative paths.
+#[derive(Debug)]
+pub struct Rules {
+    rules: Vec<(Pattern, KeepCriteria)>,
+}
+
+impl Rules {
+    pub fn load(path: &Path) -> Result<Self> {
+        let rules_error = |source: Box<dyn std::error::Error + Send + Sync>| DedupError::Rules {
+            path: path.to_path_buf(),
+            source,
+        };
+        let text = fs::read_to_string(path).at(path)?;
+        let raw: Vec<RawRule> = serde_json::from_str(&text).map_err(|e| rules_error(e.into()))?;
+        let rules = raw
+            .into_iter()
+            .map(|rule| {
+                let pattern = Pattern::new(&rule.glob).map_err(|e| rules_error(format!("{}: {}", rule.glob, e).into()))?;
+                Ok((pattern, rule.keep))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Criteria of the first rule matching any member of `group`, `None` if no rule does
+    pub fn keep_for(&self, group: &[FileInfo]) -> Option<KeepCriteria> {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| group.iter().any(|f| pattern.matches(&f.rel_path.replace('\\', "/"))))
+            .map(|(_, keep)| *keep)
+    }
+}
@@ -0,0 +1,185 @@
+use crate::error::{IoResultExt, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Shell dialect of an emitted script
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Shell {
+    Sh,
+    PowerShell,
+}
+
+impl Shell {
+    /// PowerShell on Windows, POSIX sh everywhere else
+    pub fn native() -> Self {
+        if cfg!(windows) {
+            Shell::PowerShell
+        } else {
+            Shell::Sh
+        }
+    }
+
+    /// Quote a path as a single literal argument, nothing inside is expanded
+    pub fn quote(self, path: &Path) -> String {
+        let s = path.to_string_lossy();
+        match self {
+            Shell::Sh => format!("'{}'", s.replace('\'', r"'\''")),
+            // PowerShell also treats the typographic single quotes as quote characters
+            Shell::PowerShell => {
+                let mut quoted = String::with_capacity(s.len() + 2);
+                quoted.push('\'');
+                for c in s.chars() {
+                    if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}') {
+                        quoted.push(c);
+                    }
+                    quoted.push(c);
+                }
+                quoted.push('\'');
+                quoted
+            }
+        }
+    }
+}
+
+/// Replayable script of the file operations a run would perform
+pub struct Script {
+    shell: Shell,
+    out: BufWriter<File>,
+}
+
+impl Script {
+    pub fn create(path: &Path, shell: Shell) -> Result<Self> {
+        let mut out = BufWriter::new(File::create(path).at(path)?);
+        match shell {
+            Shell::Sh => writeln!(out, "#!/bin/sh\nset -e")?,
+            Shell::PowerShell => writeln!(out, "$ErrorActionPreference = 'Stop'")?,
+        }
+        Ok(Self { shell, out })
+    }
+
+    pub fn comment(&mut self, text: &str) -> Result<()> {
+        // A newline would end the comment and turn the rest into a command
+        writeln!(self.out, "\n# {}", text.replace(['\n', '\r'], " "))?;
+        Ok(())
+    }
+
+    pub fn delete(&mut self, path: &Path) -> Result<()> {
+        let path = self.shell.quote(path);
+        match self.shell {
+            Shell::Sh => writeln!(self.out, "rm -f -- {}", path)?,
+            Shell::PowerShell => writeln!(self.out, "Remove-Item -LiteralPath {} -Force", path)?,
+        }
+        Ok(())
+    }
+
+    /// Replace `link` with a symlink to `target`
+    pub fn symlink(&mut self, target: &Path, link: &Path) -> Result<()> {
+        let (target, link) = (self.shell.quote(target), self.shell.quote(link));
+        match self.shell {
+            Shell::Sh => writeln!(self.out, "rm -f -- {} && ln -s -- {} {}", link, target, link)?,
+            Shell::PowerShell => writeln!(
+                self.out,
+                "Remove-Item -LiteralPath {} -Force; New-Item -ItemType SymbolicLink -Path {} -Target {} | Out-Null",
+                link, link, target
+            )?,
+        }
+        Ok(())
+    }
+
+    /// Replace `link` with a hardlink to `target`
+    pub fn hardlink(&mut self, target: &Path, link: &Path) -> Result<()> {
+        let (target, link) = (self.shell.quote(target), self.shell.quote(link));
+        match self.shell {
+            Shell::Sh => writeln!(self.out, "rm -f -- {} && ln -- {} {}", link, target, link)?,
+            Shell::PowerShell => writeln!(
+                self.out,
+                "Remove-Item -LiteralPath {} -Force; New-Item -ItemType HardLink -Path {} -Target {} | Out-Null",
+                link, link, target
+            )?,
+        }
+        Ok(())
+    }
+
+    /// Move `from` to `to`, creating the destination's directory first
+    pub fn move_file(&mut self, from: &Path, to: &Path) -> Result<()> {
+        let dir = self.shell.quote(to.parent().unwrap_or(Path::new(".")));
+        let (from, to) = (self.shell.quote(from), self.shell.quote(to));
+        match self.shell {
+            Shell::Sh => writeln!(self.out, "mkdir -p -- {} && mv -- {} {}", dir, from, to)?,
+            Shell::PowerShell => writeln!(
+                self.out,
+                "New-Item -ItemType Directory -Force -Path {} | Out-Null; Move-Item -LiteralPath {} -Destination {}",
+                dir, from, to
+            )?,
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoting_keeps_paths_literal() {
+        let path = Path::new("it's $HOME/`x`; rm -rf ~");
+        assert_eq!(Shell::Sh.quote(path), r"'it'\''s $HOME/`x`; rm -rf ~'");
+        assert_eq!(Shell::PowerShell.quote(path), "'it''s $HOME/`x`; rm -rf ~'");
+        assert_eq!(Shell::PowerShell.quote(Path::new("a\u{2019}b")), "'a\u{2019}\u{2019}b'");
+    }
+
+    #[test]
+    fn script_lists_the_planned_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("plan.sh");
+        let mut script = Script::create(&path, Shell::Sh).unwrap();
+        script.comment("Group 1:\nkeep a's").unwrap();
+        script.delete(Path::new("dup's.txt")).unwrap();
+        script.symlink(Path::new("keep.txt"), Path::new("link me.txt")).unwrap();
+        script.move_file(Path::new("x.txt"), Path::new("trash/run 1/x.txt")).unwrap();
+        script.finish().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "#!/bin/sh\nset -e\n\
+             \n# Group 1: keep a's\n\
+             rm -f -- 'dup'\\''s.txt'\n\
+             rm -f -- 'link me.txt' && ln -s -- 'keep.txt' 'link me.txt'\n\
+             mkdir -p -- 'trash/run 1' && mv -- 'x.txt' 'trash/run 1/x.txt'\n"
+        );
+
+        let path = dir.path().join("plan.ps1");
+        let mut script = Script::create(&path, Shell::PowerShell).unwrap();
+        script.hardlink(Path::new("keep.txt"), Path::new("it's.txt")).unwrap();
+        script.finish().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "$ErrorActionPreference = 'Stop'\n\
+             Remove-Item -LiteralPath 'it''s.txt' -Force; \
+             New-Item -ItemType HardLink -Path 'it''s.txt' -Target 'keep.txt' | Out-Null\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sh_script_runs_on_awkward_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let name = dir.path().join("it's $HOME `x`; *.txt");
+        std::fs::write(&name, "dup").unwrap();
+        std::fs::write(dir.path().join("keep.txt"), "dup").unwrap();
+        let path = dir.path().join("plan.sh");
+        let mut script = Script::create(&path, Shell::Sh).unwrap();
+        script.delete(&name).unwrap();
+        script.finish().unwrap();
+
+        let status = std::process::Command::new("sh").arg(&path).status().unwrap();
+        assert!(status.success());
+        assert!(!name.exists());
+        assert!(dir.path().join("keep.txt").exists());
+    }
+}
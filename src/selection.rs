@@ -0,0 +1,260 @@
+use crate::models::{FileInfo, KeepCriteria};
+use crate::utils::image_pixels;
+use md5::{Digest, Md5};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Compare two files by a single keep criteria, the preferred one ordering first
+fn compare_by(a: &FileInfo, b: &FileInfo, criteria: KeepCriteria, pixels: &HashMap<PathBuf, u64>) -> Ordering {
+    match criteria {
+        KeepCriteria::Latest => b.keep_time.unwrap_or(b.mtime).cmp(&a.keep_time.unwrap_or(a.mtime)),
+        KeepCriteria::Oldest => a.keep_time.unwrap_or(a.mtime).cmp(&b.keep_time.unwrap_or(b.mtime)),
+        KeepCriteria::Highest => a.key_path.len().cmp(&b.key_path.len()),
+        KeepCriteria::Deepest => b.key_path.len().cmp(&a.key_path.len()),
+        KeepCriteria::First => a.key_path.cmp(&b.key_path),
+        KeepCriteria::Last => b.key_path.cmp(&a.key_path),
+        KeepCriteria::HighestResolution => {
+            let resolution = |f: &FileInfo| {
+                let pixels = if f.archive_entry.is_none() { pixels.get(&f.path) } else { None };
+                (pixels.copied().unwrap_or(0), f.size)
+            };
+            resolution(b).cmp(&resolution(a))
+        }
+        KeepCriteria::Deterministic => {
+            let digest = |f: &FileInfo| Md5::digest(f.key_path.replace('\\', "/"));
+            digest(a).cmp(&digest(b))
+        }
+    }
+}
+
+/// Image pixel counts of a group's members. Headers are only read if a criteria needs them.
+fn pixel_counts(group: &[FileInfo], keep: KeepCriteria, secondary: KeepCriteria) -> HashMap<PathBuf, u64> {
+    if ![keep, secondary].contains(&KeepCriteria::HighestResolution) {
+        return HashMap::new();
+    }
+    group
+        .iter()
+        .filter(|f| f.archive_entry.is_none())
+        .filter_map(|f| Some((f.path.clone(), image_pixels(&f.path)?)))
+        .collect()
+}
+
+/// Full preference order of two members, the one to keep first
+fn prefer(a: &FileInfo, b: &FileInfo, keep: KeepCriteria, secondary: KeepCriteria, pixels: &HashMap<PathBuf, u64>) -> Ordering {
+    // Never keep a symlink or archive entry while a regular file is available
+    (a.archive_entry.is_some(), a.link_target.is_some())
+        .cmp(&(b.archive_entry.is_some(), b.link_target.is_some()))
+        .then_with(|| compare_by(a, b, keep, pixels))
+        .then_with(|| compare_by(a, b, secondary, pixels))
+        .then_with(|| a.rel_path.cmp(&b.rel_path))
+}
+
+/// Order a group so that the file to keep comes first. Ties on `keep` are
+/// broken by `secondary`, then by path, so the choice is reproducible.
+pub fn sort_group(group: &mut [FileInfo], keep: KeepCriteria, secondary: KeepCriteria) {
+    let pixels = pixel_counts(group, keep, secondary);
+    group.sort_by(|a, b| prefer(a, b, keep, secondary, &pixels));
+}
+
+/// Index of the member [`sort_group`] would put first, leaving the group as it
+/// is. `None` for an empty group, there is nothing to keep.
+pub fn choose_keeper(group: &[FileInfo], keep: KeepCriteria, secondary: KeepCriteria) -> Option<usize> {
+    let pixels = pixel_counts(group, keep, secondary);
+    (0..group.len()).min_by(|&a, &b| prefer(&group[a], &group[b], keep, secondary, &pixels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::ValueEnum;
+
+    fn file(rel_path: &str) -> FileInfo {
+        FileInfo {
+            path: PathBuf::from(rel_path),
+            rel_path: rel_path.to_string(),
+            key_path: rel_path.to_string(),
+            size: 100,
+            mtime: 0,
+            keep_time: Some(0),
+            inode: None,
+            dev: None,
+            link_target: None,
+            archive_entry: None,
+            allocated: None,
+        }
+    }
+
+    fn timed(rel_path: &str, time: u64) -> FileInfo {
+        FileInfo { mtime: time, keep_time: Some(time), ..file(rel_path) }
+    }
+
+    fn rel_paths(group: &[FileInfo]) -> Vec<&str> {
+        group.iter().map(|f| f.rel_path.as_str()).collect()
+    }
+
+    #[test]
+    fn each_criteria_picks_its_keeper() {
+        let group = [timed("b/mid/copy.txt", 20), timed("a.txt", 30), timed("c/very/deep/copy.txt", 10)];
+        let cases = [
+            (KeepCriteria::Latest, 1, "a.txt"),
+            (KeepCriteria::Oldest, 2, "c/very/deep/copy.txt"),
+            (KeepCriteria::Highest, 1, "a.txt"),
+            (KeepCriteria::Deepest, 2, "c/very/deep/copy.txt"),
+            (KeepCriteria::First, 1, "a.txt"),
+            (KeepCriteria::Last, 2, "c/very/deep/copy.txt"),
+            (KeepCriteria::HighestResolution, 0, "b/mid/copy.txt"),
+        ];
+        for (criteria, index, keeper) in cases {
+            let mut group = group.to_vec();
+            if criteria == KeepCriteria::HighestResolution {
+                group[0].size = 200;
+            }
+            assert_eq!(choose_keeper(&group, criteria, KeepCriteria::First), Some(index), "{:?}", criteria);
+            assert_eq!(group[index].rel_path, keeper, "{:?}", criteria);
+            sort_group(&mut group, criteria, KeepCriteria::First);
+            assert_eq!(group[0].rel_path, keeper, "{:?}", criteria);
+        }
+
+        let expected = group.iter().min_by_key(|f| Md5::digest(&f.key_path)).unwrap().rel_path.clone();
+        let index = choose_keeper(&group, KeepCriteria::Deterministic, KeepCriteria::First).unwrap();
+        assert_eq!(group[index].rel_path, expected);
+    }
+
+    #[test]
+    fn keep_time_falls_back_to_the_mtime() {
+        let group = [timed("a.txt", 5), FileInfo { keep_time: None, ..timed("b.txt", 9) }];
+        assert_eq!(choose_keeper(&group, KeepCriteria::Latest, KeepCriteria::First), Some(1));
+        assert_eq!(choose_keeper(&group, KeepCriteria::Oldest, KeepCriteria::First), Some(0));
+    }
+
+    #[test]
+    fn empty_group_has_no_keeper() {
+        assert_eq!(choose_keeper(&[], KeepCriteria::Latest, KeepCriteria::First), None);
+    }
+
+    #[test]
+    fn single_member_is_always_kept() {
+        for &criteria in KeepCriteria::value_variants() {
+            let group = [timed("only.txt", 3)];
+            assert_eq!(choose_keeper(&group, criteria, KeepCriteria::First), Some(0), "{:?}", criteria);
+        }
+    }
+
+    #[test]
+    fn equal_timestamps_fall_through_to_secondary_then_path() {
+        let group = [timed("b/x.txt", 7), timed("a/long/x.txt", 7), timed("c.txt", 7)];
+        for criteria in [KeepCriteria::Latest, KeepCriteria::Oldest] {
+            assert_eq!(choose_keeper(&group, criteria, KeepCriteria::Highest), Some(2));
+            let mut sorted = group.to_vec();
+            sort_group(&mut sorted, criteria, KeepCriteria::Highest);
+            assert_eq!(rel_paths(&sorted), ["c.txt", "b/x.txt", "a/long/x.txt"]);
+
+            // A secondary that ties as well leaves the relative path
+            assert_eq!(choose_keeper(&group, criteria, KeepCriteria::Latest), Some(1));
+            let mut sorted = group.to_vec();
+            sort_group(&mut sorted, criteria, KeepCriteria::Latest);
+            assert_eq!(rel_paths(&sorted), ["a/long/x.txt", "b/x.txt", "c.txt"]);
+        }
+    }
+
+    #[test]
+    fn equal_path_lengths_fall_through_to_secondary_then_path() {
+        let group = [timed("b/x.txt", 1), timed("c/x.txt", 3), timed("a/x.txt", 2)];
+        for criteria in [KeepCriteria::Highest, KeepCriteria::Deepest] {
+            assert_eq!(choose_keeper(&group, criteria, KeepCriteria::Latest), Some(1));
+            let mut sorted = group.to_vec();
+            sort_group(&mut sorted, criteria, KeepCriteria::Latest);
+            assert_eq!(rel_paths(&sorted), ["c/x.txt", "a/x.txt", "b/x.txt"]);
+
+            assert_eq!(choose_keeper(&group, criteria, KeepCriteria::Deepest), Some(2));
+            let mut sorted = group.to_vec();
+            sort_group(&mut sorted, criteria, KeepCriteria::Deepest);
+            assert_eq!(rel_paths(&sorted), ["a/x.txt", "b/x.txt", "c/x.txt"]);
+        }
+    }
+
+    #[test]
+    fn regular_files_win_over_links_and_archive_entries_whatever_the_criteria() {
+        let link = FileInfo { link_target: Some(PathBuf::from("target.txt")), ..timed("a-link.txt", 9) };
+        let entry = FileInfo { archive_entry: Some("a.txt".to_string()), ..timed("a.zip", 9) };
+        for &criteria in KeepCriteria::value_variants() {
+            let mut group = vec![entry.clone(), link.clone(), timed("z/regular.txt", 1)];
+            assert_eq!(choose_keeper(&group, criteria, KeepCriteria::First), Some(2), "{:?}", criteria);
+            // A link still beats an archive entry
+            assert_eq!(choose_keeper(&group[..2], criteria, KeepCriteria::First), Some(1), "{:?}", criteria);
+            sort_group(&mut group, criteria, KeepCriteria::First);
+            assert_eq!(rel_paths(&group), ["z/regular.txt", "a-link.txt", "a.zip"], "{:?}", criteria);
+        }
+    }
+
+    #[test]
+    fn highest_resolution_keeps_the_larger_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = |name: &str, width: u32, height: u32| {
+            let path = dir.path().join(name);
+            image::RgbImage::new(width, height).save(&path).unwrap();
+            FileInfo { path, ..file(name) }
+        };
+        let mut group = vec![image("small.png", 16, 16), image("large.png", 64, 48), image("wide.png", 100, 10)];
+
+        sort_group(&mut group, KeepCriteria::HighestResolution, KeepCriteria::First);
+        let order: Vec<&str> = group.iter().map(|f| f.rel_path.as_str()).collect();
+        assert_eq!(order, ["large.png", "wide.png", "small.png"]);
+
+        // Non-images fall back to the larger size
+        let mut group = vec![file("a.bin"), FileInfo { size: 200, ..file("b.bin") }];
+        sort_group(&mut group, KeepCriteria::HighestResolution, KeepCriteria::First);
+        assert_eq!(group[0].rel_path, "b.bin");
+    }
+
+    #[test]
+    fn secondary_criteria_breaks_a_primary_tie() {
+        let same_time = |rel_path: &str| FileInfo { mtime: 5, keep_time: Some(5), ..file(rel_path) };
+        let group = [
+            same_time("b/copy.txt"),
+            same_time("a/deeper/copy.txt"),
+            FileInfo { mtime: 1, keep_time: Some(1), ..file("c.txt") },
+        ];
+
+        let mut by_first = group.to_vec();
+        sort_group(&mut by_first, KeepCriteria::Latest, KeepCriteria::First);
+        assert_eq!(by_first[0].rel_path, "a/deeper/copy.txt");
+
+        let mut by_highest = group.to_vec();
+        sort_group(&mut by_highest, KeepCriteria::Latest, KeepCriteria::Highest);
+        assert_eq!(by_highest[0].rel_path, "b/copy.txt");
+
+        // The older file never wins, whatever breaks the tie
+        assert_eq!(by_first[2].rel_path, "c.txt");
+        assert_eq!(by_highest[2].rel_path, "c.txt");
+    }
+
+    #[test]
+    fn deterministic_keeper_ignores_member_order() {
+        let paths = ["photos/a.jpg", "backup/photos/a.jpg", "z.jpg", "old/2019/a.jpg"];
+        let expected = *paths.iter().min_by_key(|p| Md5::digest(p)).unwrap();
+        // Every rotation of every pair swap, timestamps that disagree included
+        for swap in 0..paths.len() {
+            for rotation in 0..paths.len() {
+                let mut group: Vec<FileInfo> = paths
+                    .iter()
+                    .enumerate()
+                    .map(|(i, p)| FileInfo { mtime: i as u64, keep_time: Some(i as u64), ..file(p) })
+                    .collect();
+                group.swap(0, swap);
+                group.rotate_left(rotation);
+                sort_group(&mut group, KeepCriteria::Deterministic, KeepCriteria::Latest);
+                assert_eq!(group[0].rel_path, expected);
+            }
+        }
+
+        // Windows separators hash like the forward slashes of a mirrored tree elsewhere
+        let windows = FileInfo { key_path: r"backup\photos\a.jpg".to_string(), ..file("backup/photos/a.jpg") };
+        let mut group = vec![file("photos/a.jpg"), windows];
+        sort_group(&mut group, KeepCriteria::Deterministic, KeepCriteria::First);
+        let mut mirrored = vec![file("photos/a.jpg"), file("backup/photos/a.jpg")];
+        sort_group(&mut mirrored, KeepCriteria::Deterministic, KeepCriteria::First);
+        assert_eq!(group[0].key_path.replace('\\', "/"), mirrored[0].key_path);
+    }
+}
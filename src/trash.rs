@@ -0,0 +1,309 @@
+use crate::error::{DedupError, IoResultExt, Result};
+use crate::platform::create_symlink;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MANIFEST_FILE: &str = "manifest.csv";
+
+/// One trashed file as recorded in the trash manifest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashEntry {
+    /// Location inside the trash directory, relative to it
+    pub trashed_path: String,
+    /// Absolute path the file was moved away from
+    pub original_path: PathBuf,
+    /// Unix timestamp (seconds) of the move
+    pub trashed_at: u64,
+    /// Run that moved it, empty for entries recorded before run ids existed
+    #[serde(default)]
+    pub run_id: String,
+}
+
+/// App-managed trash directory with a manifest of where each file came from
+pub struct Trash {
+    dir: PathBuf,
+    /// Recorded with every file this run moves in
+    run_id: String,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Like `Path::exists`, but true for (possibly dangling) symlinks too
+fn present(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+/// Rename, falling back to copy + delete when crossing filesystems
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if fs::rename(from, to).is_err() {
+        copy_and_remove(from, to)?;
+    }
+    Ok(())
+}
+
+/// Move a file by copying it. A symlink is recreated pointing at the same
+/// file rather than replaced by a copy of its target.
+fn copy_and_remove(from: &Path, to: &Path) -> Result<()> {
+    if fs::symlink_metadata(from).at(from)?.file_type().is_symlink() {
+        let target = fs::read_link(from).at(from)?;
+        // A relative target would resolve against the new location
+        let target = match from.parent() {
+            Some(parent) if target.is_relative() => parent.join(target),
+            _ => target,
+        };
+        create_symlink(&target, to)?;
+    } else {
+        fs::copy(from, to).at(to)?;
+    }
+    fs::remove_file(from).at(from)?;
+    Ok(())
+}
+
+impl Trash {
+    pub fn new(dir: PathBuf, run_id: String) -> Self {
+        Self { dir, run_id }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.dir.join(MANIFEST_FILE)
+    }
+
+    /// Where `move_in` would put a file with `rel_path` if it were trashed now
+    pub fn planned_path(&self, rel_path: &str) -> PathBuf {
+        self.dir.join(now_secs().to_string()).join(rel_path)
+    }
+
+    /// Move a file into the trash, keeping its relative layout below a
+    /// per-run timestamp directory, and record it in the manifest
+    pub fn move_in(&self, path: &Path, rel_path: &str) -> Result<PathBuf> {
+        let trashed_at = now_secs();
+        let mut trashed_path = Path::new(&trashed_at.to_string()).join(rel_path);
+        let mut n = 1;
+        while present(&self.dir.join(&trashed_path)) {
+            trashed_path = Path::new(&format!("{}-{}", trashed_at, n)).join(rel_path);
+            n += 1;
+        }
+        let parent = path.parent().unwrap_or(Path::new("."));
+        let original_path = fs::canonicalize(parent).at(parent)?.join(path.file_name().unwrap_or_default());
+        let dest = self.dir.join(&trashed_path);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).at(parent)?;
+        }
+        move_file(path, &dest)?;
+
+        self.append(&TrashEntry {
+            trashed_path: trashed_path.to_string_lossy().into_owned(),
+            original_path,
+            trashed_at,
+            run_id: self.run_id.clone(),
+        })?;
+        Ok(dest)
+    }
+
+    fn manifest_error(&self, source: csv::Error) -> DedupError {
+        DedupError::Manifest {
+            path: self.manifest_path(),
+            source,
+        }
+    }
+
+    /// Whether the manifest has every column of [`TrashEntry`], older ones lack `run_id`
+    fn has_current_columns(&self) -> Result<bool> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(b';')
+            .from_path(self.manifest_path())
+            .map_err(|e| self.manifest_error(e))?;
+        let headers = rdr.headers().map_err(|e| self.manifest_error(e))?;
+        Ok(headers.iter().any(|h| h == "run_id"))
+    }
+
+    fn append(&self, entry: &TrashEntry) -> Result<()> {
+        let manifest = self.manifest_path();
+        let needs_header = !manifest.exists() || fs::metadata(&manifest).at(&manifest)?.len() == 0;
+        // Rows with the new column can't go below an old header, rewrite it first
+        if !needs_header && !self.has_current_columns()? {
+            let mut entries = self.entries()?;
+            entries.push(entry.clone());
+            return self.write_entries(&entries);
+        }
+        let file = fs::OpenOptions::new().create(true).append(true).open(&manifest).at(&manifest)?;
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b';')
+            .has_headers(needs_header)
+            .from_writer(file);
+        wtr.serialize(entry).map_err(|e| self.manifest_error(e))?;
+        wtr.flush().at(&manifest)?;
+        Ok(())
+    }
+
+    /// All entries currently recorded in the manifest
+    pub fn entries(&self) -> Result<Vec<TrashEntry>> {
+        let manifest = self.manifest_path();
+        if !manifest.exists() {
+            return Ok(Vec::new());
+        }
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(b';')
+            .from_path(manifest)
+            .map_err(|e| self.manifest_error(e))?;
+        rdr.deserialize()
+            .collect::<std::result::Result<Vec<TrashEntry>, _>>()
+            .map_err(|e| self.manifest_error(e))
+    }
+
+    fn write_entries(&self, entries: &[TrashEntry]) -> Result<()> {
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b';')
+            .from_path(self.manifest_path())
+            .map_err(|e| self.manifest_error(e))?;
+        for entry in entries {
+            wtr.serialize(entry).map_err(|e| self.manifest_error(e))?;
+        }
+        wtr.flush().at(&self.manifest_path())?;
+        Ok(())
+    }
+
+    /// Remove directories left empty below the trash root after `trashed_path` went away
+    fn prune_dirs(&self, trashed_path: &str) {
+        let mut dir = self.dir.join(trashed_path);
+        while let Some(parent) = dir.parent() {
+            if parent == self.dir || fs::remove_dir(parent).is_err() {
+                break;
+            }
+            dir = parent.to_path_buf();
+        }
+    }
+
+    /// Move every trashed file back to its original location. Files whose
+    /// original path is occupied again stay in the trash, entries whose file
+    /// was removed from the trash by hand are dropped. Returns the restored entries.
+    pub fn restore(&self) -> Result<Vec<TrashEntry>> {
+        let mut restored = Vec::new();
+        let mut remaining = Vec::new();
+        for entry in self.entries()? {
+            let source = self.dir.join(&entry.trashed_path);
+            if !present(&source) {
+                continue;
+            }
+            if present(&entry.original_path) {
+                remaining.push(entry);
+                continue;
+            }
+            if let Some(parent) = entry.original_path.parent() {
+                fs::create_dir_all(parent).at(parent)?;
+            }
+            move_file(&source, &entry.original_path)?;
+            self.prune_dirs(&entry.trashed_path);
+            restored.push(entry);
+        }
+        self.write_entries(&remaining)?;
+        Ok(restored)
+    }
+
+    /// Permanently delete trashed files older than `older_than`. Returns the purged entries.
+    pub fn purge(&self, older_than: Duration) -> Result<Vec<TrashEntry>> {
+        let cutoff = now_secs().saturating_sub(older_than.as_secs());
+        let (purged, remaining): (Vec<TrashEntry>, Vec<TrashEntry>) =
+            self.entries()?.into_iter().partition(|e| e.trashed_at <= cutoff);
+        for entry in &purged {
+            let path = self.dir.join(&entry.trashed_path);
+            if present(&path) {
+                fs::remove_file(&path).at(&path)?;
+            }
+            self.prune_dirs(&entry.trashed_path);
+        }
+        self.write_entries(&remaining)?;
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A trash directory and a scan root with `names` in it
+    fn setup(names: &[&str]) -> (tempfile::TempDir, Trash, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        for name in names {
+            fs::write(root.join(name), name).unwrap();
+        }
+        let trash = Trash::new(dir.path().join("trash"), "run-1".to_string());
+        fs::create_dir_all(trash.dir()).unwrap();
+        (dir, trash, root)
+    }
+
+    #[test]
+    fn moved_files_are_recorded_and_restored() {
+        let (_dir, trash, root) = setup(&["sub/a.txt", "b.txt"]);
+        let dest = trash.move_in(&root.join("sub/a.txt"), "sub/a.txt").unwrap();
+        trash.move_in(&root.join("b.txt"), "b.txt").unwrap();
+
+        assert!(!root.join("sub/a.txt").exists());
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "sub/a.txt");
+        let entries = trash.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].original_path, fs::canonicalize(&root).unwrap().join("sub/a.txt"));
+        assert_eq!(trash.dir().join(&entries[0].trashed_path), dest);
+        assert_eq!(entries[0].run_id, "run-1");
+
+        // An occupied original path keeps its file in the trash
+        fs::write(root.join("b.txt"), "new").unwrap();
+        let restored = trash.restore().unwrap();
+        assert_eq!(restored.len(), 1);
+        assert_eq!(fs::read_to_string(root.join("sub/a.txt")).unwrap(), "sub/a.txt");
+        assert_eq!(fs::read_to_string(root.join("b.txt")).unwrap(), "new");
+        let remaining = trash.entries().unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert!(trash.dir().join(&remaining[0].trashed_path).exists());
+    }
+
+    #[test]
+    fn purge_deletes_only_entries_past_the_retention() {
+        let (_dir, trash, root) = setup(&["old.txt", "new.txt"]);
+        let old = trash.move_in(&root.join("old.txt"), "old.txt").unwrap();
+        let new = trash.move_in(&root.join("new.txt"), "new.txt").unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let mut entries = trash.entries().unwrap();
+        entries[0].trashed_at -= 2 * day.as_secs();
+        trash.write_entries(&entries).unwrap();
+
+        let purged = trash.purge(day).unwrap();
+        assert_eq!(purged.len(), 1);
+        assert_eq!(purged[0].original_path.file_name().unwrap(), "old.txt");
+        assert!(!old.exists());
+        assert!(new.exists());
+        assert_eq!(trash.entries().unwrap().len(), 1);
+
+        assert_eq!(trash.purge(day).unwrap().len(), 0);
+        assert_eq!(trash.purge(Duration::ZERO).unwrap().len(), 1);
+        assert!(!new.exists());
+        assert!(trash.entries().unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copying_a_symlink_moves_the_link() {
+        let (_dir, trash, root) = setup(&["target.txt"]);
+        std::os::unix::fs::symlink("target.txt", root.join("link.txt")).unwrap();
+        let dest = trash.dir().join("link.txt");
+
+        copy_and_remove(&root.join("link.txt"), &dest).unwrap();
+        assert!(!present(&root.join("link.txt")));
+        assert!(fs::symlink_metadata(&dest).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_link(&dest).unwrap(), root.join("target.txt"));
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "target.txt");
+    }
+}
@@ -0,0 +1,107 @@
+use crate::error::Result;
+use crate::models::{FileInfo, Mode};
+use crate::review::Review;
+use crate::utils::format_bytes;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListItem, ListState, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+
+const HELP: &str = " ↑/↓ group  ←/→ file  space keep file  a action  enter apply all  q quit ";
+
+fn action_label(action: Option<Mode>) -> String {
+    action.map_or("skip".to_string(), |mode| mode.verb().to_string())
+}
+
+fn format_mtime(f: &FileInfo) -> String {
+    chrono::DateTime::from_timestamp_nanos(f.mtime as i64)
+        .with_timezone(&chrono::Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+fn draw(frame: &mut Frame, review: &Review) {
+    let [main, help] = Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+    let [groups_area, members_area] =
+        Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(main);
+
+    let items: Vec<ListItem> = review
+        .groups()
+        .iter()
+        .enumerate()
+        .map(|(i, (_, group))| {
+            let decision = review.decision(i);
+            ListItem::new(format!(
+                "[{:>8}] {} x {}  {}",
+                action_label(decision.action),
+                group.len(),
+                format_bytes(group[0].size),
+                group[decision.keep].rel_path
+            ))
+        })
+        .collect();
+    let groups = List::new(items)
+        .block(Block::bordered().title(format!(" {} duplicate groups ", review.groups().len())))
+        .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+    let mut group_state = ListState::default().with_selected(Some(review.selected()));
+    frame.render_stateful_widget(groups, groups_area, &mut group_state);
+
+    if let Some((key, group)) = review.groups().get(review.selected()) {
+        let decision = review.decision(review.selected());
+        let rows = group.iter().enumerate().map(|(i, f)| {
+            let role = if i == decision.keep {
+                "keep".to_string()
+            } else {
+                action_label(decision.action)
+            };
+            Row::new([role, format_bytes(f.size), format_mtime(f), f.rel_path.clone()])
+        });
+        let members = Table::new(
+            rows,
+            [Constraint::Length(9), Constraint::Length(10), Constraint::Length(19), Constraint::Min(10)],
+        )
+        .header(Row::new(["Role", "Size", "Modified", "Path"]).bold())
+        .block(Block::bordered().title(format!(" {} ", key)))
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        let mut member_state = TableState::default().with_selected(Some(review.member()));
+        frame.render_stateful_widget(members, members_area, &mut member_state);
+    }
+
+    frame.render_widget(Line::from(HELP).reversed(), help);
+}
+
+/// Drive `review` until the user applies (`true`) or quits (`false`)
+fn run(terminal: &mut DefaultTerminal, review: &mut Review) -> Result<bool> {
+    loop {
+        terminal.draw(|frame| draw(frame, review))?;
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => review.prev_group(),
+            KeyCode::Down | KeyCode::Char('j') => review.next_group(),
+            KeyCode::Left | KeyCode::Char('h') => review.prev_member(),
+            KeyCode::Right | KeyCode::Char('l') => review.next_member(),
+            KeyCode::Char(' ') => review.keep_member(),
+            KeyCode::Char('a') => review.cycle_action(),
+            KeyCode::Enter => return Ok(true),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(false),
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(false),
+            _ => {}
+        }
+    }
+}
+
+/// Let the user triage `review` full screen. Returns `None` if they quit
+/// without applying, the terminal is restored either way.
+pub fn triage(mut review: Review) -> Result<Option<Review>> {
+    let mut terminal = ratatui::try_init()?;
+    let applied = run(&mut terminal, &mut review);
+    ratatui::restore();
+    Ok(applied?.then_some(review))
+}
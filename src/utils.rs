@@ -1,13 +1,11 @@
 use sysinfo::Disks;
-use std::path::Path;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 pub fn get_raw_disk_info(path: &Path, disks: &Disks) -> Option<(u64, u64)> {
     let path_str = path.to_string_lossy();
-    let normalized_path = if path_str.starts_with(r"\\?\") {
-        &path_str[4..]
-    } else {
-        &path_str
-    };
+    let normalized_path = path_str.strip_prefix(r"\\?\").unwrap_or(&path_str);
     let normalized_path = Path::new(normalized_path);
 
     for disk in disks {
@@ -24,10 +22,121 @@ pub fn format_disk_info(free: u64, total: u64) -> String {
     } else {
         0.0
     };
-    format!(
-        "{:.2}/{:.2}GB ({:.1}%)",
-        free as f64 / 1_073_741_824.0,
-        total as f64 / 1_073_741_824.0,
-        percent
-    )
+    format!("{}/{} ({:.1}%)", format_bytes(free), format_bytes(total), percent)
+}
+
+/// Identify a run by its start time and process id, unique for runs on one machine
+pub fn generate_run_id() -> String {
+    format!("{}-{}", chrono::Local::now().format("%Y%m%dT%H%M%S"), std::process::id())
+}
+
+/// Differences in free space below this are put down to other processes
+const RECLAIM_SLACK: u64 = 16 * 1024 * 1024;
+
+/// Whether the free space actually gained, `freed`, is far off the `expected`
+/// reclaim: off by more than a tenth of it and by more than [`RECLAIM_SLACK`]
+pub fn reclaim_diverges(expected: u64, freed: u64) -> bool {
+    let difference = expected.abs_diff(freed);
+    difference > RECLAIM_SLACK && difference > expected / 10
+}
+
+/// Format a byte count with binary units (B, KB, MB, GB, TB), matching `parse_size`
+pub fn format_bytes(bytes: u64) -> String {
+    if bytes == u64::MAX {
+        return "∞".to_string();
+    }
+    const TB: u64 = 1024 * 1024 * 1024 * 1024;
+    const GB: u64 = 1024 * 1024 * 1024;
+    const MB: u64 = 1024 * 1024;
+    const KB: u64 = 1024;
+
+    if bytes >= TB {
+        format!("{:.2} TB", bytes as f64 / TB as f64)
+    } else if bytes >= GB {
+        format!("{:.2} GB", bytes as f64 / GB as f64)
+    } else if bytes >= MB {
+        format!("{:.2} MB", bytes as f64 / MB as f64)
+    } else if bytes >= KB {
+        format!("{:.2} KB", bytes as f64 / KB as f64)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+/// Pixel count of an image, read from its header only. `None` for non-images.
+pub fn image_pixels(path: &Path) -> Option<u64> {
+    let (width, height) = image::image_dimensions(path).ok()?;
+    Some(width as u64 * height as u64)
+}
+
+/// Directories below `root` that are (or would be, once `removed` is gone)
+/// empty, counting directories that only hold other such directories. Only
+/// ancestors of `removed` are considered and `root` itself never is. Returned
+/// deepest first, so they can be removed in order.
+pub fn empty_dirs_after(root: &Path, removed: &HashSet<PathBuf>) -> Vec<PathBuf> {
+    let mut candidates: Vec<PathBuf> = removed
+        .iter()
+        .filter(|p| p.starts_with(root))
+        .flat_map(|p| p.ancestors().skip(1).take_while(|d| *d != root))
+        .map(Path::to_path_buf)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    candidates.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+
+    let mut empty: Vec<PathBuf> = Vec::new();
+    for dir in candidates {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        let vacated = entries.flatten().all(|e| {
+            let path = e.path();
+            removed.contains(&path) || empty.contains(&path)
+        });
+        if vacated {
+            empty.push(dir);
+        }
+    }
+    empty
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_bytes_switches_units_at_binary_boundaries() {
+        const KB: u64 = 1024;
+        const MB: u64 = 1024 * KB;
+        const GB: u64 = 1024 * MB;
+        const TB: u64 = 1024 * GB;
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.00 KB");
+        assert_eq!(format_bytes(1536), "1.50 KB");
+        assert_eq!(format_bytes(MB - 1), "1024.00 KB");
+        assert_eq!(format_bytes(MB), "1.00 MB");
+        assert_eq!(format_bytes(GB - 1), "1024.00 MB");
+        assert_eq!(format_bytes(GB), "1.00 GB");
+        assert_eq!(format_bytes(TB), "1.00 TB");
+        assert_eq!(format_bytes(2048 * TB), "2048.00 TB");
+        assert_eq!(format_bytes(u64::MAX), "∞");
+    }
+
+    #[test]
+    fn directories_emptied_by_the_run_are_pruned_below_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for rel in ["gone/deep/a.txt", "gone/b.txt", "kept/c.txt", "kept/d.txt", "e.txt"] {
+            let path = root.join(rel);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "x").unwrap();
+        }
+        // Already removed in a real run, still there in a dry run
+        fs::remove_file(root.join("gone/b.txt")).unwrap();
+        let removed: HashSet<PathBuf> =
+            ["gone/deep/a.txt", "gone/b.txt", "kept/c.txt", "e.txt"].iter().map(|rel| root.join(rel)).collect();
+
+        assert_eq!(empty_dirs_after(root, &removed), [root.join("gone/deep"), root.join("gone")]);
+    }
 }
@@ -0,0 +1,703 @@
+//! End-to-end runs of the binary on temporary trees
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, SystemTime};
+
+/// Write `contents` to `rel` below `root`, creating its directories
+fn write(root: &Path, rel: &str, contents: impl AsRef<[u8]>) {
+    let path = root.join(rel);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+/// Run the binary on `root` with stdin detached, like a script would
+fn run(root: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_duplicates"))
+        .arg("-p")
+        .arg(root)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8_lossy(&output.stdout).replace('\\', "/")
+}
+
+/// Groups of a JSON report as (kept file, duplicates), sorted for comparison
+fn report_groups(path: &Path) -> Vec<(String, Vec<String>)> {
+    let report: serde_json::Value = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+    let mut groups: Vec<(String, Vec<String>)> = report
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|g| {
+            let keep = g["keep"].as_str().unwrap().replace('\\', "/");
+            let duplicates = g["duplicates"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|d| d.as_str().unwrap().replace('\\', "/"))
+                .collect();
+            (keep, duplicates)
+        })
+        .collect();
+    groups.sort();
+    groups
+}
+
+/// Dry run over `root` with `args`, returning the groups it reported
+fn dry_run_groups(root: &Path, args: &[&str]) -> Vec<(String, Vec<String>)> {
+    let out = tempfile::tempdir().unwrap();
+    let report = out.path().join("report.json");
+    let report_arg = report.to_str().unwrap();
+    let mut all_args = vec!["-r", "-d", "--min-size", "0", "--report", report_arg];
+    all_args.extend_from_slice(args);
+    let output = run(root, &all_args);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    report_groups(&report)
+}
+
+fn group(keep: &str, duplicates: &[&str]) -> (String, Vec<String>) {
+    (keep.to_string(), duplicates.iter().map(|d| d.to_string()).collect())
+}
+
+#[test]
+fn same_dir_only_keeps_directories_apart() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "a/x.txt", "same");
+    write(dir.path(), "a/y.txt", "same");
+    write(dir.path(), "b/x.txt", "same");
+
+    assert_eq!(dry_run_groups(dir.path(), &["-k", "first"]), [group("a/x.txt", &["a/y.txt", "b/x.txt"])]);
+    assert_eq!(
+        dry_run_groups(dir.path(), &["-k", "first", "--same-dir-only"]),
+        [group("a/x.txt", &["a/y.txt"])]
+    );
+}
+
+#[test]
+fn verify_integrity_detects_corruption_behind_unchanged_metadata() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "a.bin", "original content");
+    write(dir.path(), "b.bin", "original content");
+    // Hashing the candidates records the baseline in the cache
+    dry_run_groups(dir.path(), &["-k", "first"]);
+
+    let clean = run(dir.path(), &["-r", "--min-size", "0", "verify-integrity"]);
+    assert!(clean.status.success(), "{}", String::from_utf8_lossy(&clean.stderr));
+
+    let path = dir.path().join("a.bin");
+    let modified = fs::metadata(&path).unwrap().modified().unwrap();
+    fs::write(&path, "corrupt content!").unwrap();
+    fs::File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+
+    let corrupted = run(dir.path(), &["-r", "--min-size", "0", "verify-integrity"]);
+    assert!(!corrupted.status.success());
+    let log = String::from_utf8_lossy(&corrupted.stdout);
+    assert!(log.contains("CORRUPTED a.bin"), "{}", log);
+    assert!(!log.contains("b.bin"), "{}", log);
+    assert!(log.contains("Verified 2 file(s), 1 mismatch(es)."), "{}", log);
+}
+
+#[test]
+fn destructive_run_without_a_terminal_needs_yes() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "a.txt", "same");
+    write(dir.path(), "b.txt", "same");
+
+    let refused = run(dir.path(), &["-k", "first", "-m", "delete", "--min-size", "0"]);
+    assert!(!refused.status.success());
+    let stderr = String::from_utf8_lossy(&refused.stderr);
+    assert!(stderr.contains("Refusing to delete 1 files without --yes"), "{}", stderr);
+    assert!(dir.path().join("a.txt").exists() && dir.path().join("b.txt").exists());
+
+    let confirmed = run(dir.path(), &["-k", "first", "-m", "delete", "--min-size", "0", "--yes"]);
+    assert!(confirmed.status.success(), "{}", String::from_utf8_lossy(&confirmed.stderr));
+    assert!(dir.path().join("a.txt").exists() && !dir.path().join("b.txt").exists());
+}
+
+#[test]
+fn fdupes_format_lists_groups_separated_by_blank_lines() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "a/1.txt", "alpha");
+    write(dir.path(), "a/2.txt", "alpha");
+    write(dir.path(), "b/x.bin", "beta beta");
+    write(dir.path(), "c/y.bin", "beta beta");
+    write(dir.path(), "c/z.bin", "beta beta");
+    write(dir.path(), "unique.txt", "solo");
+
+    // No --keep needed just to list
+    let listed = run(dir.path(), &["-r", "--min-size", "0", "--format", "fdupes"]);
+    assert_eq!(stdout(&listed), "a/1.txt\na/2.txt\n\nb/x.bin\nc/y.bin\nc/z.bin\n\n");
+
+    let listed = run(dir.path(), &["-r", "--min-size", "0", "--format", "fdupes", "-k", "last"]);
+    assert_eq!(stdout(&listed), "a/2.txt\na/1.txt\n\nc/z.bin\nc/y.bin\nb/x.bin\n\n");
+
+    let root = fs::canonicalize(dir.path()).unwrap();
+    let absolute = |rel: &str| root.join(rel).display().to_string().replace('\\', "/");
+    let listed = run(dir.path(), &["-r", "--min-size", "0", "--format", "fdupes", "--absolute-paths"]);
+    let expected = format!("{}\n{}\n\n", absolute("a/1.txt"), absolute("a/2.txt"));
+    assert!(stdout(&listed).starts_with(&expected), "{}", stdout(&listed));
+    assert!(dir.path().join("a/2.txt").is_file());
+}
+
+#[cfg(unix)]
+#[test]
+fn broken_symlink_is_logged_and_counted() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "file.txt", "content");
+    std::os::unix::fs::symlink(dir.path().join("missing.txt"), dir.path().join("dangling.txt")).unwrap();
+
+    let log = stdout(&run(dir.path(), &["-k", "first", "-d", "--min-size", "0"]));
+    assert!(log.contains("Warning: broken symlink dangling.txt"), "{}", log);
+    assert!(log.contains("Warnings: 1 broken symlink(s), 0 symlink chain(s)"), "{}", log);
+}
+
+#[test]
+fn batched_hashing_finds_the_same_groups() {
+    let dir = tempfile::tempdir().unwrap();
+    // Eight size clusters of five files, each holding two pairs and a unique file
+    for len in 10..18 {
+        for copy in 0..5 {
+            let fill = ["a", "b", "c"][copy % 3];
+            write(dir.path(), &format!("{}/{}.txt", len, copy), fill.repeat(len));
+        }
+    }
+
+    let all_at_once = dry_run_groups(dir.path(), &["-k", "first"]);
+    assert_eq!(all_at_once.len(), 16);
+    let batched = dry_run_groups(dir.path(), &["-k", "first", "--max-candidates", "12"]);
+    assert_eq!(batched, all_at_once);
+
+    let log = fs::read_to_string(dir.path().join("duplicates.log")).unwrap();
+    assert!(log.contains("40 candidates exceed --max-candidates 12, hashing in 4 batches"), "{}", log);
+    // The first run's hashes are found batch by batch
+    assert!(log.contains("Batch 4/4: Cache: 10 hits, 0 files"), "{}", log);
+}
+
+#[test]
+fn hardlinked_aliases_are_represented_by_the_keep_rule() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "deep/er/original.txt", "shared");
+    fs::hard_link(dir.path().join("deep/er/original.txt"), dir.path().join("alias.txt")).unwrap();
+    write(dir.path(), "sub/copy.txt", "shared");
+
+    // The inode counts once, through the path the rule prefers
+    assert_eq!(dry_run_groups(dir.path(), &["-k", "highest"]), [group("alias.txt", &["sub/copy.txt"])]);
+    assert_eq!(
+        dry_run_groups(dir.path(), &["-k", "deepest"]),
+        [group("deep/er/original.txt", &["sub/copy.txt"])]
+    );
+    assert_eq!(dry_run_groups(dir.path(), &["-k", "last"]), [group("sub/copy.txt", &["deep/er/original.txt"])]);
+}
+
+#[test]
+fn excluded_sizes_are_skipped_and_others_grouped() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "placeholder1.bin", [0u8; 4]);
+    write(dir.path(), "placeholder2.bin", [0u8; 4]);
+    write(dir.path(), "thumb1.bin", [0u8; 1024]);
+    write(dir.path(), "thumb2.bin", [0u8; 1024]);
+    write(dir.path(), "photo1.bin", [1u8; 10]);
+    write(dir.path(), "photo2.bin", [1u8; 10]);
+
+    assert_eq!(dry_run_groups(dir.path(), &["-k", "first"]).len(), 3);
+    assert_eq!(
+        dry_run_groups(dir.path(), &["-k", "first", "--exclude-sizes", "4,1KB"]),
+        [group("photo1.bin", &["photo2.bin"])]
+    );
+}
+
+/// A message of the same length as `original`, with another first byte, whose
+/// CRC32 is the same. CRC32 is linear over GF(2) for a fixed length, so the
+/// last four bytes can be solved for.
+fn crc32_collision(original: &[u8]) -> Vec<u8> {
+    let len = original.len();
+    let zero_crc = crc32fast::hash(&vec![0; len]);
+    // Linear part of the CRC of a message that only has `bit` set
+    let flip = |bit: usize| {
+        let mut message = vec![0u8; len];
+        message[bit / 8] ^= 1 << (bit % 8);
+        crc32fast::hash(&message) ^ zero_crc
+    };
+    let tail_bits: Vec<usize> = ((len - 4) * 8..len * 8).collect();
+
+    // Gaussian elimination: express the first byte's flip through tail bit flips
+    let mut target = flip(0);
+    let mut rows: Vec<(u32, u64)> = tail_bits.iter().enumerate().map(|(i, &bit)| (flip(bit), 1u64 << i)).collect();
+    let mut chosen = 0u64;
+    for pivot in 0..32 {
+        let mask = 1u32 << pivot;
+        let Some(index) = rows.iter().position(|(value, _)| value & mask != 0) else {
+            continue;
+        };
+        let row = rows.swap_remove(index);
+        for other in rows.iter_mut().filter(|(value, _)| value & mask != 0) {
+            other.0 ^= row.0;
+            other.1 ^= row.1;
+        }
+        if target & mask != 0 {
+            target ^= row.0;
+            chosen ^= row.1;
+        }
+    }
+    assert_eq!(target, 0, "the tail bits span every CRC");
+
+    let mut collision = original.to_vec();
+    collision[0] ^= 1;
+    for (i, &bit) in tail_bits.iter().enumerate() {
+        if chosen & (1 << i) != 0 {
+            collision[bit / 8] ^= 1 << (bit % 8);
+        }
+    }
+    collision
+}
+
+#[test]
+fn confirm_with_splits_a_crafted_collision() {
+    let original = b"an innocent file with some content".to_vec();
+    let forged = crc32_collision(&original);
+    assert_ne!(forged, original);
+    assert_eq!(crc32fast::hash(&forged), crc32fast::hash(&original));
+
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "original.txt", &original);
+    write(dir.path(), "forged.txt", &forged);
+
+    assert_eq!(dry_run_groups(dir.path(), &["-k", "first", "-a", "crc32"]).len(), 1);
+    assert!(dry_run_groups(dir.path(), &["-k", "first", "-a", "crc32", "--confirm-with", "md5"]).is_empty());
+    let log = fs::read_to_string(dir.path().join("duplicates.log")).unwrap();
+    assert!(log.contains("1 groups did not fully match under Md5 and were split"), "{}", log);
+}
+
+#[test]
+fn unique_report_lists_exactly_the_ungrouped_files() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "a.txt", "same");
+    write(dir.path(), "sub/b.txt", "same");
+    // Same size as the duplicates, so it is hashed before it turns out unique
+    write(dir.path(), "c.txt", "diff");
+    write(dir.path(), "sub/d.txt", "a different size");
+    let out = tempfile::tempdir().unwrap();
+    let unique = out.path().join("unique.txt");
+
+    let groups = dry_run_groups(dir.path(), &["-k", "first", "--unique-report", unique.to_str().unwrap()]);
+    assert_eq!(groups, [group("a.txt", &["sub/b.txt"])]);
+    let listed = fs::read_to_string(&unique).unwrap().replace('\\', "/");
+    assert_eq!(listed, "c.txt\nsub/d.txt\n");
+}
+
+#[test]
+fn ignored_natural_keeper_is_warned_about() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "best/top.txt", "same");
+    write(dir.path(), "deep/er/1.txt", "same");
+    write(dir.path(), "deep/er/2.txt", "same");
+    // Only equal in canonical form, so grouped under a canonical key
+    write(dir.path(), "best/doc.json", r#"{"a":1,"b":2}"#);
+    write(dir.path(), "deep/er/1.json", r#"{"b": 2, "a": 1}"#);
+    write(dir.path(), "deep/er/2.json", r#"{ "b":2,"a":1 }"#);
+
+    let args = ["-r", "-d", "--min-size", "0", "-k", "highest", "-i", "best", "--canonical-structured"];
+    let log = stdout(&run(dir.path(), &args));
+    assert!(log.contains("keeping deep/er/1.txt, but the ignored best/top.txt would have been kept"), "{}", log);
+    assert!(log.contains("keeping deep/er/1.json, but the ignored best/doc.json would have been kept"), "{}", log);
+
+    // Below --max-depth the ignored copy would never have been found either
+    let shallow = tempfile::tempdir().unwrap();
+    write(shallow.path(), "best/deep/top.txt", "same");
+    write(shallow.path(), "1.txt", "same");
+    write(shallow.path(), "2.txt", "same");
+    let args = ["-r", "-d", "--min-size", "0", "-k", "last", "-i", "best", "--max-depth", "2"];
+    let log = stdout(&run(shallow.path(), &args));
+    assert!(log.contains("Keeping 2.txt"), "{}", log);
+    assert!(!log.contains("would have been kept"), "{}", log);
+}
+
+#[cfg(unix)]
+#[test]
+fn time_source_picks_the_timestamp_the_keeper_is_chosen_by() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "a.txt", "same");
+    write(dir.path(), "b.txt", "same");
+    // a is older by mtime but newer by atime. Hashing may bump access times, so
+    // the atime run comes first: its discovery reads them before anything is hashed.
+    let now = SystemTime::now();
+    for (name, modified, accessed) in [("a.txt", 1000, 10), ("b.txt", 500, 100)] {
+        let times = fs::FileTimes::new()
+            .set_modified(now - Duration::from_secs(modified))
+            .set_accessed(now - Duration::from_secs(accessed));
+        fs::File::options().write(true).open(dir.path().join(name)).unwrap().set_times(times).unwrap();
+    }
+
+    assert_eq!(
+        dry_run_groups(dir.path(), &["-k", "latest", "--time-source", "atime"]),
+        [group("a.txt", &["b.txt"])]
+    );
+    assert_eq!(dry_run_groups(dir.path(), &["-k", "latest"]), [group("b.txt", &["a.txt"])]);
+}
+
+#[test]
+fn files_above_full_hash_max_are_grouped_by_size_and_samples() {
+    let dir = tempfile::tempdir().unwrap();
+    // Zero-filled, so every sample of the huge files is alike whatever their size
+    write(dir.path(), "huge/a.bin", vec![0u8; 3 << 20]);
+    write(dir.path(), "huge/b.bin", vec![0u8; 3 << 20]);
+    write(dir.path(), "large/a.bin", vec![0u8; 2 << 20]);
+    write(dir.path(), "large/b.bin", vec![0u8; 2 << 20]);
+    write(dir.path(), "small/a.txt", "small");
+    write(dir.path(), "small/b.txt", "small");
+    write(dir.path(), "small/c.txt", "other");
+    let out = tempfile::tempdir().unwrap();
+    let report = out.path().join("report.json");
+
+    // One size cluster per batch, so equal keys would have to meet across batches
+    let args = [
+        "-r", "-d", "--min-size", "0", "-k", "first", "--full-hash-max", "1MB", "--max-candidates", "2",
+        "--report", report.to_str().unwrap(),
+    ];
+    let log = stdout(&run(dir.path(), &args));
+    assert_eq!(
+        report_groups(&report),
+        [
+            group("huge/a.bin", &["huge/b.bin"]),
+            group("large/a.bin", &["large/b.bin"]),
+            group("small/a.txt", &["small/b.txt"]),
+        ]
+    );
+    assert!(log.contains("(sampled hash): Keeping huge/a.bin"), "{}", log);
+    assert!(log.contains("(sampled hash): Keeping large/a.bin"), "{}", log);
+    assert!(log.contains("(full hash): Keeping small/a.txt"), "{}", log);
+}
+
+#[test]
+fn path_transform_aligns_mirrored_trees() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "backup1/photos/a.jpg", "first copy");
+    write(dir.path(), "backup2/photos/a.jpg", "second copy");
+    write(dir.path(), "backup2/photos/b.jpg", "first copy");
+    let mirrored = [group("backup1/photos/a.jpg", &["backup2/photos/a.jpg"])];
+
+    assert_eq!(dry_run_groups(dir.path(), &["-k", "first", "-a", "path"]), []);
+    assert_eq!(
+        dry_run_groups(dir.path(), &["-k", "first", "-a", "path", "--path-strip", "backup1", "--path-strip", "backup2"]),
+        mirrored
+    );
+    assert_eq!(
+        dry_run_groups(dir.path(), &["-k", "first", "-a", "path", "--path-regex-replace", r"^backup\d", ""]),
+        mirrored
+    );
+}
+
+#[test]
+fn empty_files_are_only_merged_by_name_or_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    for rel in ["a/x.txt", "a/y.txt", "b/x.txt", "b/z.txt"] {
+        write(dir.path(), rel, "");
+    }
+
+    assert_eq!(
+        dry_run_groups(dir.path(), &["-k", "first"]),
+        [group("a/x.txt", &["a/y.txt", "b/x.txt", "b/z.txt"])]
+    );
+    assert_eq!(
+        dry_run_groups(dir.path(), &["-k", "first", "--hash-empty-as-distinct", "name"]),
+        [group("a/x.txt", &["b/x.txt"])]
+    );
+    assert_eq!(
+        dry_run_groups(dir.path(), &["-k", "first", "--hash-empty-as-distinct", "dir"]),
+        [group("a/x.txt", &["a/y.txt"]), group("b/x.txt", &["b/z.txt"])]
+    );
+}
+
+#[test]
+fn files_modified_within_the_window_are_skipped() {
+    let dir = tempfile::tempdir().unwrap();
+    let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    for name in ["a.txt", "b.txt"] {
+        write(dir.path(), name, "same");
+        fs::File::options().write(true).open(dir.path().join(name)).unwrap().set_modified(hour_ago).unwrap();
+    }
+    // Still being downloaded, as far as the run can tell
+    write(dir.path(), "c.txt", "same");
+
+    assert_eq!(dry_run_groups(dir.path(), &["-k", "first"]), [group("a.txt", &["b.txt", "c.txt"])]);
+    let args = ["-r", "-d", "--min-size", "0", "-k", "first", "--skip-recently-modified", "60s"];
+    let log = stdout(&run(dir.path(), &args));
+    assert!(log.contains("Skipped 1 files modified within the last 60s"), "{}", log);
+    assert!(!log.contains("c.txt"), "{}", log);
+}
+
+#[test]
+fn compare_accepts_options_after_the_subcommand() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "a/orig.txt", "shared");
+    write(dir.path(), "a/other.txt", "only in a");
+    write(dir.path(), "b/copy.txt", "shared");
+    write(dir.path(), "b/x/copy.txt", "shared");
+    write(dir.path(), "b/unique.txt", "only in b");
+    fs::create_dir(dir.path().join("log")).unwrap();
+    let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+    let compare = |args: &[&str]| {
+        let mut all_args = vec!["compare", a.to_str().unwrap(), b.to_str().unwrap()];
+        all_args.extend_from_slice(args);
+        run(&dir.path().join("log"), &all_args)
+    };
+
+    let log = stdout(&compare(&["-r", "--min-size", "0", "-m", "delete", "--dry-run"]));
+    assert!(log.contains("[DRY RUN] copy.txt -> Delete"), "{}", log);
+    assert!(log.contains("[DRY RUN] x/copy.txt -> Delete"), "{}", log);
+    assert!(!log.contains("unique.txt"), "{}", log);
+
+    let refused = compare(&["-r", "--min-size", "0", "-m", "delete"]);
+    assert!(!refused.status.success());
+    let stderr = String::from_utf8_lossy(&refused.stderr);
+    assert!(stderr.contains("Refusing to delete 2 files without --yes"), "{}", stderr);
+    assert!(b.join("copy.txt").exists());
+
+    stdout(&compare(&["-r", "--min-size", "0", "-m", "delete", "--yes"]));
+    assert!(!b.join("copy.txt").exists() && !b.join("x/copy.txt").exists());
+    assert!(b.join("unique.txt").exists());
+    assert!(a.join("orig.txt").exists() && a.join("other.txt").exists());
+}
+
+#[test]
+fn rules_pick_the_keep_criteria_per_group() {
+    let dir = tempfile::tempdir().unwrap();
+    let now = SystemTime::now();
+    for (rel, contents, age) in [
+        ("work/draft.txt", "work", 100),
+        ("work/final.txt", "work", 10),
+        ("archive/old.txt", "archived", 100),
+        ("archive/new.txt", "archived", 10),
+        ("misc/b.txt", "misc", 100),
+        ("misc/a.txt", "misc", 10),
+    ] {
+        write(dir.path(), rel, contents);
+        let modified = now - Duration::from_secs(age);
+        fs::File::options().write(true).open(dir.path().join(rel)).unwrap().set_modified(modified).unwrap();
+    }
+    let out = tempfile::tempdir().unwrap();
+    let rules = out.path().join("rules.json");
+    fs::write(&rules, r#"[{"glob": "work/**", "keep": "latest"}, {"glob": "archive/**", "keep": "oldest"}]"#).unwrap();
+
+    // Groups no rule matches fall back to --keep
+    assert_eq!(
+        dry_run_groups(dir.path(), &["-k", "first", "--rules", rules.to_str().unwrap()]),
+        [
+            group("archive/old.txt", &["archive/new.txt"]),
+            group("misc/a.txt", &["misc/b.txt"]),
+            group("work/final.txt", &["work/draft.txt"]),
+        ]
+    );
+}
+
+/// A hardlink shares (dev, inode) with its original just like the same file seen
+/// through a bind mount does, without needing the privileges to mount anything
+#[cfg(unix)]
+#[test]
+fn compare_leaves_aliases_of_the_kept_files_alone() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "a/orig.txt", "shared");
+    write(dir.path(), "b/copy.txt", "shared");
+    fs::hard_link(dir.path().join("a/orig.txt"), dir.path().join("b/alias.txt")).unwrap();
+    fs::create_dir(dir.path().join("log")).unwrap();
+    let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+
+    let args = ["compare", a.to_str().unwrap(), b.to_str().unwrap(), "--min-size", "0", "-m", "delete", "--yes"];
+    let log = stdout(&run(&dir.path().join("log"), &args));
+    assert!(log.contains("alias.txt is orig.txt"), "{}", log);
+    assert!(log.contains("1 file(s)"), "{}", log);
+    assert!(!b.join("copy.txt").exists());
+    assert_eq!(fs::read_to_string(b.join("alias.txt")).unwrap(), "shared");
+    assert_eq!(fs::read_to_string(a.join("orig.txt")).unwrap(), "shared");
+}
+
+#[test]
+fn inverted_size_range_is_an_error_and_a_single_size_a_warning() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "a.txt", "same");
+    write(dir.path(), "b.txt", "same");
+    write(dir.path(), "c.txt", "other");
+    write(dir.path(), "d.txt", "other");
+
+    let inverted = run(dir.path(), &["-k", "first", "-d", "--min-size", "2GB", "--max-size", "1GB"]);
+    assert!(!inverted.status.success());
+    let stderr = String::from_utf8_lossy(&inverted.stderr);
+    assert!(stderr.contains("--min-size 2.00 GB is larger than --max-size 1.00 GB, no file could match"), "{}", stderr);
+
+    // Looking for files of one exact size is legitimate, if unusual
+    let exact = stdout(&run(dir.path(), &["-k", "first", "-d", "--min-size", "4", "--max-size", "4"]));
+    assert!(exact.contains("Warning: --min-size equals --max-size, only files of exactly 4 B can match"), "{}", exact);
+    assert!(exact.contains("[DRY RUN] b.txt"), "{}", exact);
+    assert!(!exact.contains("d.txt"), "{}", exact);
+}
+
+#[cfg(unix)]
+#[test]
+fn second_symlink_run_performs_no_actions() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "a/orig.txt", "shared");
+    write(dir.path(), "b/copy.txt", "shared");
+    let args = ["-r", "-k", "first", "--min-size", "0", "-m", "symlink", "--yes"];
+    let first = stdout(&run(dir.path(), &args));
+    assert!(first.contains("Symlinked b/copy.txt"), "{}", first);
+    let link = dir.path().join("b/copy.txt");
+    let inode = fs::symlink_metadata(&link).unwrap().ino();
+    assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+
+    // Links are only grouped with their target when their targets are hashed
+    for extra in [&[][..], &["--hash-symlink-targets"][..]] {
+        let again = stdout(&run(dir.path(), &[&args[..], extra].concat()));
+        assert!(!again.contains("Symlinked"), "{}", again);
+        assert_eq!(fs::symlink_metadata(&link).unwrap().ino(), inode, "the link was recreated");
+        if !extra.is_empty() {
+            assert!(again.contains("[LINKED] b/copy.txt already points at a/orig.txt"), "{}", again);
+        }
+    }
+    assert_eq!(fs::read_to_string(dir.path().join("a/orig.txt")).unwrap(), "shared");
+}
+
+/// The `--summary-json` object on the last line of stdout, checked against the schema
+fn summary_line(output: &Output) -> serde_json::Map<String, serde_json::Value> {
+    let stdout = stdout(output);
+    let line = stdout.lines().last().unwrap_or_default();
+    let summary: serde_json::Map<String, serde_json::Value> = serde_json::from_str(line).unwrap();
+    let mut fields: Vec<&str> = summary.keys().map(String::as_str).collect();
+    fields.sort();
+    let expected = ["bytes_reclaimable", "duplicates", "elapsed_ms", "errors", "files_scanned", "groups", "run_id"];
+    assert_eq!(fields, expected, "{}", line);
+    assert!(summary["run_id"].is_string(), "{}", line);
+    assert!(expected[..6].iter().all(|field| summary[*field].is_u64()), "{}", line);
+    summary
+}
+
+#[test]
+fn summary_json_is_the_last_line_of_stdout() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "a/one.txt", "same");
+    write(dir.path(), "b/two.txt", "same");
+    write(dir.path(), "c.txt", "different");
+    let args = ["-r", "-k", "first", "--min-size", "0", "-d", "--summary-json", "--run-id", "nightly"];
+
+    for quiet in [&[][..], &["-q"][..]] {
+        let summary = summary_line(&run(dir.path(), &[&args[..], quiet].concat()));
+        assert_eq!(summary["run_id"], "nightly");
+        assert_eq!(summary["files_scanned"], 3);
+        assert_eq!(summary["groups"], 1);
+        assert_eq!(summary["duplicates"], 1);
+        assert_eq!(summary["bytes_reclaimable"], 4);
+        assert_eq!(summary["errors"], 0);
+    }
+
+    // The dry run cached the hashes verify-integrity checks against
+    let verified = summary_line(&run(dir.path(), &["-r", "--min-size", "0", "--summary-json", "verify-integrity"]));
+    assert_eq!(verified["files_scanned"], 3);
+    assert_eq!(verified["groups"], 0);
+
+    let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+    fs::create_dir(dir.path().join("log")).unwrap();
+    let compare = ["compare", a.to_str().unwrap(), b.to_str().unwrap(), "--min-size", "0", "-d", "--summary-json"];
+    let compared = summary_line(&run(&dir.path().join("log"), &compare));
+    assert_eq!(compared["files_scanned"], 2);
+    assert_eq!(compared["groups"], 1);
+    assert_eq!(compared["duplicates"], 1);
+    assert_eq!(compared["bytes_reclaimable"], 4);
+}
+
+#[cfg(unix)]
+#[test]
+fn self_referential_symlinked_directory_is_walked_once() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "a/file.txt", "only");
+    std::os::unix::fs::symlink(dir.path().join("a"), dir.path().join("a/loop")).unwrap();
+
+    let args = ["-r", "--follow-symlinks", "-k", "first", "--min-size", "0", "-d"];
+    let log = stdout(&run(dir.path(), &args));
+    assert!(log.contains("Warning: directory loop at a/loop, not entered again"), "{}", log);
+    assert!(log.contains("Found 1 total files"), "{}", log);
+
+    // Incremental runs plan the walk themselves, the state they record included
+    for _ in 0..2 {
+        let log = stdout(&run(dir.path(), &[&args[..], &["--incremental"]].concat()));
+        assert!(log.contains("Warning: directory loop at a/loop, not entered again"), "{}", log);
+        assert!(log.contains("Found 1 total files"), "{}", log);
+    }
+}
+
+#[test]
+fn documents_differing_only_in_key_order_group_when_canonical() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "a.json", r#"{"name": "backup", "nested": {"b": 1, "a": [1, 2]}}"#);
+    write(dir.path(), "b.json", "{\n  \"nested\": {\"a\": [1, 2], \"b\": 1},\n  \"name\": \"backup\"\n}\n");
+    write(dir.path(), "c.json", r#"{"name": "backup", "nested": {"b": 1, "a": [2, 1]}}"#);
+    write(dir.path(), "a.xml", r#"<config mode="fast" level="2"><item>x</item></config>"#);
+    write(dir.path(), "b.xml", "<config level=\"2\" mode=\"fast\">\n  <item>x</item>\n</config>");
+    write(dir.path(), "broken.json", r#"{"unterminated": "#);
+    write(dir.path(), "broken-copy.json", r#"{"unterminated": "#);
+
+    // Malformed documents are still compared byte for byte
+    let broken = group("broken-copy.json", &["broken.json"]);
+    assert_eq!(dry_run_groups(dir.path(), &["-k", "first"]), std::slice::from_ref(&broken));
+    let canonical = dry_run_groups(dir.path(), &["-k", "first", "--canonical-structured"]);
+    assert_eq!(canonical, [group("a.json", &["b.json"]), group("a.xml", &["b.xml"]), broken]);
+
+    let log = stdout(&run(dir.path(), &["-r", "-d", "--min-size", "0", "-k", "first", "--canonical-structured"]));
+    let warned = |name: &str| log.lines().any(|l| l.contains("WARNING: Malformed document") && l.contains(name));
+    assert!(warned("/broken.json") && warned("/broken-copy.json") && !warned("/a.json"), "{}", log);
+}
+
+/// Run id of a log line, which reads "[<timestamp>] [<run id>] <message>"
+fn logged_run_id(line: &str) -> Option<&str> {
+    line.split("] [").nth(1)?.split(']').next()
+}
+
+/// Run with `args` and a report at `report`, returning the run id every log
+/// line and the summary agree on
+fn run_id_of_reported_run(root: &Path, args: &[&str], report: &Path) -> String {
+    let mut all_args = vec!["-r", "-d", "--min-size", "0", "-k", "first", "--summary-json", "--report"];
+    all_args.push(report.to_str().unwrap());
+    all_args.extend_from_slice(args);
+    let logged = stdout(&run(root, &all_args));
+    let (log_lines, summary) = logged.trim_end().rsplit_once('\n').unwrap();
+    let id = logged_run_id(log_lines).unwrap().to_string();
+    assert!(log_lines.lines().all(|l| logged_run_id(l) == Some(id.as_str())), "{}", logged);
+    let on_disk = fs::read_to_string(root.join("duplicates.log")).unwrap();
+    assert_eq!(on_disk.trim_end().replace('\\', "/"), log_lines);
+    let summary: serde_json::Value = serde_json::from_str(summary).unwrap();
+    assert_eq!(summary["run_id"], id.as_str());
+    id
+}
+
+#[test]
+fn run_id_is_the_same_across_log_report_and_summary() {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "a.txt", "same");
+    write(dir.path(), "b.txt", "same");
+    let out = tempfile::tempdir().unwrap();
+    let (json, csv) = (out.path().join("report.json"), out.path().join("report.csv"));
+
+    for supplied in [None, Some("ci-job-42")] {
+        let args: Vec<&str> = supplied.map(|id| vec!["--run-id", id]).unwrap_or_default();
+
+        let id = run_id_of_reported_run(dir.path(), &args, &json);
+        assert!(supplied.is_none_or(|supplied| id == supplied), "{}", id);
+        let report: serde_json::Value = serde_json::from_slice(&fs::read(&json).unwrap()).unwrap();
+        assert!(report.as_array().unwrap().iter().all(|g| g["run_id"] == id.as_str()), "{}", report);
+
+        let id = run_id_of_reported_run(dir.path(), &args, &csv);
+        assert!(supplied.is_none_or(|supplied| id == supplied), "{}", id);
+        let mut rows = csv::ReaderBuilder::new().delimiter(b';').from_path(&csv).unwrap();
+        let column = rows.headers().unwrap().iter().position(|h| h == "run_id").unwrap();
+        let ids: Vec<String> = rows.records().map(|r| r.unwrap()[column].to_string()).collect();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.iter().all(|r| *r == id), "{:?}", ids);
+    }
+}